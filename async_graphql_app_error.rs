@@ -0,0 +1,90 @@
+use std::sync::Arc;
+
+use async_graphql::{ErrorExtensionValues, ErrorExtensions, Value};
+use axum::http::StatusCode;
+
+use super::axum_anyhow_error::{AppError, ErrorKind};
+use super::request_id::RequestId;
+
+pub const EXTENSION_CODE: &str = "code";
+pub const EXTENSION_HTTP_STATUS: &str = "httpStatus";
+pub const EXTENSION_ERROR_ID: &str = "errorId";
+
+// resolverで `?` したときに使われる
+impl From<AppError> for async_graphql::Error {
+    fn from(err: AppError) -> Self {
+        let code = err.code();
+        let status = err.status();
+        // 5xxはpublic_messageに原因が出ないので、ここで報告してerrorIdで追えるようにする
+        let error_id = status
+            .is_server_error()
+            .then(|| err.report(RequestId::current().as_ref()).0);
+        let mut error = async_graphql::Error::new(err.public_message()).extend_with(|_, e| {
+            e.set(EXTENSION_CODE, code);
+            e.set(EXTENSION_HTTP_STATUS, status.as_u16());
+            if let Some(error_id) = error_id {
+                e.set(EXTENSION_ERROR_ID, error_id.to_string());
+            }
+        });
+        // from_graphql_errorで元に戻せるようにsourceに残す
+        error.source = Some(Arc::new(err));
+        error
+    }
+}
+
+pub fn from_graphql_error(mut err: async_graphql::Error) -> AppError {
+    if let Some(source) = err.source.take()
+        && let Ok(app_error) = source.downcast::<AppError>()
+    {
+        match Arc::try_unwrap(app_error) {
+            Ok(app_error) => return app_error,
+            Err(app_error) => {
+                return AppError::msg(app_error.kind(), app_error.error().to_string())
+                    .with_status(app_error.status());
+            }
+        }
+    }
+
+    let kind = error_code(err.extensions.as_ref())
+        .and_then(|code| ErrorKind::from_code(&code))
+        .unwrap_or(ErrorKind::Internal);
+    let app_error = AppError::msg(kind, err.message);
+    match http_status(err.extensions.as_ref()).and_then(|status| StatusCode::from_u16(status).ok())
+    {
+        Some(status) => app_error.with_status(status),
+        None => app_error,
+    }
+}
+
+/// GraphQLのサービスコードをRESTのhandlerから呼ぶとき用
+pub trait GraphqlResultExt<T> {
+    fn into_app_result(self) -> Result<T, AppError>;
+}
+
+impl<T> GraphqlResultExt<T> for async_graphql::Result<T> {
+    fn into_app_result(self) -> Result<T, AppError> {
+        self.map_err(from_graphql_error)
+    }
+}
+
+pub fn error_code(extensions: Option<&ErrorExtensionValues>) -> Option<String> {
+    match extensions?.get(EXTENSION_CODE)? {
+        Value::String(code) => Some(code.clone()),
+        Value::Enum(code) => Some(code.to_string()),
+        _ => None,
+    }
+}
+
+pub fn http_status(extensions: Option<&ErrorExtensionValues>) -> Option<u16> {
+    match extensions?.get(EXTENSION_HTTP_STATUS)? {
+        Value::Number(status) => status
+            .as_u64()
+            .and_then(|status| u16::try_from(status).ok()),
+        _ => None,
+    }
+}
+
+/// httpStatusが4xxのエラー。Sentryへの送信やspanのエラー扱いから外す
+pub fn is_client_error(extensions: Option<&ErrorExtensionValues>) -> bool {
+    http_status(extensions).is_some_and(|status| (400..500).contains(&status))
+}
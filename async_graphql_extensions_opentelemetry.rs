@@ -1,7 +1,9 @@
 use std::collections::HashMap;
 use std::sync::Arc;
 
-use async_graphql::parser::types::ExecutableDocument;
+use async_graphql::parser::types::{
+    DocumentOperations, ExecutableDocument, Field, OperationDefinition, Selection, SelectionSet,
+};
 use async_graphql::{
     Response, ServerError, ServerResult, ValidationResult, Value,
     extensions::{
@@ -9,18 +11,22 @@ use async_graphql::{
         NextResolve, NextSubscribe, NextValidation, ResolveInfo,
     },
 };
-use async_graphql_value::{ConstValue, Variables};
+use async_graphql_value::{ConstValue, Name, Variables};
 use futures_util::{TryFutureExt, stream::BoxStream};
 use opentelemetry::{
     Context as OpenTelemetryContext, Key, KeyValue,
-    trace::{FutureExt, SpanKind, TraceContextExt, Tracer},
+    trace::{FutureExt, SpanKind, Status, TraceContextExt, Tracer},
 };
 
+use super::async_graphql_app_error::{error_code, is_client_error};
+use super::parent_trace_context::ParentTraceContext;
+
 const KEY_SOURCE: Key = Key::from_static_str("graphql.source");
 const KEY_VARIABLES: Key = Key::from_static_str("graphql.variables");
 const KEY_PARENT_TYPE: Key = Key::from_static_str("graphql.parentType");
 const KEY_RETURN_TYPE: Key = Key::from_static_str("graphql.returnType");
 const KEY_ERROR: Key = Key::from_static_str("graphql.error");
+const KEY_ERROR_CODE: Key = Key::from_static_str("graphql.error.code");
 const KEY_COMPLEXITY: Key = Key::from_static_str("graphql.complexity");
 const KEY_DEPTH: Key = Key::from_static_str("graphql.depth");
 
@@ -82,14 +88,21 @@ where
         stream: BoxStream<'s, Response>,
         next: NextSubscribe<'_>,
     ) -> BoxStream<'s, Response> {
+        // WebSocketではHTTPのspanがないので、TracedExecutorがcurrentにしたoperationごとのcontext、
+        // なければconnection_initでsessionのdataに入れたcontextを親にする
+        let current_cx = OpenTelemetryContext::current();
+        let parent_cx = match ctx.data_opt::<ParentTraceContext>() {
+            Some(parent) if !current_cx.span().span_context().is_valid() => parent.get(),
+            _ => current_cx,
+        };
+        let span = self
+            .tracer
+            .span_builder("subscribe")
+            .with_kind(SpanKind::Server)
+            .start_with_context(&*self.tracer, &parent_cx);
         Box::pin(
             next.run(ctx, stream)
-                .with_context(OpenTelemetryContext::current_with_span(
-                    self.tracer
-                        .span_builder("subscribe")
-                        .with_kind(SpanKind::Server)
-                        .start(&*self.tracer),
-                )),
+                .with_context(parent_cx.with_span(span)),
         )
     }
 
@@ -119,7 +132,7 @@ where
                     .span()
                     .set_attribute(KeyValue::new(
                         KEY_SOURCE,
-                        ctx.stringify_execute_doc(doc, variables),
+                        ctx.stringify_execute_doc(&redact_document(doc), variables),
                     ));
             }
             res
@@ -190,10 +203,15 @@ where
 
         let fut = next.run(ctx, info).inspect_err(|err| {
             let current_cx = OpenTelemetryContext::current();
-            current_cx.span().add_event(
-                "error".to_string(),
-                vec![KeyValue::new(KEY_ERROR, err.to_string())],
-            );
+            let span = current_cx.span();
+            let mut attributes = vec![KeyValue::new(KEY_ERROR, err.to_string())];
+            if let Some(code) = error_code(err.extensions.as_ref()) {
+                attributes.push(KeyValue::new(KEY_ERROR_CODE, code));
+            }
+            span.add_event("error".to_string(), attributes);
+            if !is_client_error(err.extensions.as_ref()) {
+                span.set_status(Status::error(err.message.clone()));
+            }
         });
 
         match span {
@@ -219,11 +237,87 @@ const CREDENTIAL_KEYS: [&str; 10] = [
     "credentials",
 ];
 
-fn is_credential(key: &str) -> bool {
+pub(crate) fn is_credential(key: &str) -> bool {
     CREDENTIAL_KEYS.iter().any(|k| *k == key || key.contains(k))
 }
 
-fn serialize_variables(variabls: &Variables) -> String {
+/// `stringify_execute_doc` は `#[graphql(secret)]` しか隠さないので、
+/// `login(password: "...")` のようなリテラルも変数と同じ名前のルールで隠す
+pub(crate) fn redact_document(doc: &ExecutableDocument) -> ExecutableDocument {
+    let mut doc = doc.clone();
+    match &mut doc.operations {
+        DocumentOperations::Single(operation) => redact_operation(&mut operation.node),
+        DocumentOperations::Multiple(operations) => {
+            for operation in operations.values_mut() {
+                redact_operation(&mut operation.node);
+            }
+        }
+    }
+    for fragment in doc.fragments.values_mut() {
+        redact_selection_set(&mut fragment.node.selection_set.node);
+    }
+    doc
+}
+
+fn redact_operation(operation: &mut OperationDefinition) {
+    for variable in &mut operation.variable_definitions {
+        if is_credential(variable.node.name.node.as_str())
+            && let Some(default_value) = variable.node.default_value.as_mut()
+        {
+            default_value.node = ConstValue::String("<secret>".to_string());
+        }
+    }
+    redact_selection_set(&mut operation.selection_set.node);
+}
+
+fn redact_selection_set(selection_set: &mut SelectionSet) {
+    for selection in &mut selection_set.items {
+        match &mut selection.node {
+            Selection::Field(field) => redact_field(&mut field.node),
+            Selection::InlineFragment(fragment) => {
+                redact_selection_set(&mut fragment.node.selection_set.node)
+            }
+            Selection::FragmentSpread(_) => {}
+        }
+    }
+}
+
+fn redact_field(field: &mut Field) {
+    for (name, value) in &mut field.arguments {
+        redact_value(&name.node, &mut value.node);
+    }
+    for directive in &mut field.directives {
+        for (name, value) in &mut directive.node.arguments {
+            redact_value(&name.node, &mut value.node);
+        }
+    }
+    redact_selection_set(&mut field.selection_set.node);
+}
+
+fn redact_value(name: &Name, value: &mut async_graphql_value::Value) {
+    use async_graphql_value::Value as InputValue;
+
+    match value {
+        // 変数はserialize_variablesで隠す
+        InputValue::Variable(_) => {}
+        _ if is_credential(name.as_str()) => {
+            *value = InputValue::String("<secret>".to_string());
+        }
+        InputValue::Object(fields) => {
+            for (name, value) in fields.iter_mut() {
+                redact_value(name, value);
+            }
+        }
+        InputValue::List(values) => {
+            for value in values {
+                redact_value(name, value);
+            }
+        }
+        _ => {}
+    }
+}
+
+pub(crate) fn serialize_variables(variabls: &Variables) -> String {
     let data = variabls
         .iter()
         .map(|(k, v)| {
@@ -272,3 +366,144 @@ fn serialize_const_value(value: &ConstValue) -> serde_json::Value {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use async_graphql::parser::parse_query;
+
+    use super::*;
+
+    // redact後のドキュメントの引数を "field.arg=value" の形で集める
+    fn arguments(doc: &ExecutableDocument) -> Vec<String> {
+        fn selection_set(set: &SelectionSet, out: &mut Vec<String>) {
+            for selection in &set.items {
+                match &selection.node {
+                    Selection::Field(field) => {
+                        let field = &field.node;
+                        for (name, value) in &field.arguments {
+                            out.push(format!("{}.{}={}", field.name.node, name.node, value.node));
+                        }
+                        for directive in &field.directives {
+                            for (name, value) in &directive.node.arguments {
+                                out.push(format!(
+                                    "@{}.{}={}",
+                                    directive.node.name.node, name.node, value.node
+                                ));
+                            }
+                        }
+                        selection_set(&field.selection_set.node, out);
+                    }
+                    Selection::InlineFragment(fragment) => {
+                        selection_set(&fragment.node.selection_set.node, out)
+                    }
+                    Selection::FragmentSpread(_) => {}
+                }
+            }
+        }
+
+        let mut out = vec![];
+        for (_, operation) in doc.operations.iter() {
+            for variable in &operation.node.variable_definitions {
+                if let Some(default_value) = &variable.node.default_value {
+                    out.push(format!(
+                        "${}={}",
+                        variable.node.name.node, default_value.node
+                    ));
+                }
+            }
+            selection_set(&operation.node.selection_set.node, &mut out);
+        }
+        for fragment in doc.fragments.values() {
+            selection_set(&fragment.node.selection_set.node, &mut out);
+        }
+        out.sort();
+        out
+    }
+
+    fn redacted(query: &str) -> Vec<String> {
+        arguments(&redact_document(&parse_query(query).unwrap()))
+    }
+
+    #[test]
+    fn redact_literal_arguments() {
+        assert_eq!(
+            redacted(r#"mutation { login(email: "a@example.com", password: "hunter2") { id } }"#),
+            vec![
+                r#"login.email="a@example.com""#,
+                r#"login.password="<secret>""#,
+            ]
+        );
+    }
+
+    #[test]
+    fn redact_nested_objects_and_lists() {
+        assert_eq!(
+            redacted(
+                r#"mutation {
+                    update(input: { name: "a", auth: { apiKey: "k1", scope: "x" } }, tokens: ["t1", "t2"]) { id }
+                }"#
+            ),
+            vec![
+                r#"update.input={name: "a", auth: {apiKey: "<secret>", scope: "x"}}"#,
+                r#"update.tokens="<secret>""#,
+            ]
+        );
+        assert_eq!(
+            redacted(r#"{ users(filter: [{ name: "a", password: "p" }]) { id } }"#),
+            vec![r#"users.filter=[{name: "a", password: "<secret>"}]"#]
+        );
+    }
+
+    #[test]
+    fn redact_directive_arguments() {
+        assert_eq!(
+            redacted(r#"{ me @auth(token: "abc", role: "admin") { id } }"#),
+            vec![r#"@auth.role="admin""#, r#"@auth.token="<secret>""#]
+        );
+    }
+
+    #[test]
+    fn redact_fragments() {
+        assert_eq!(
+            redacted(
+                r#"{
+                    ...Login
+                    ... on Query { verify(secret: "s", id: 1) }
+                }
+                fragment Login on Query { login(password: "p") { id } }"#
+            ),
+            vec![
+                r#"login.password="<secret>""#,
+                r#"verify.id=1"#,
+                r#"verify.secret="<secret>""#,
+            ]
+        );
+    }
+
+    #[test]
+    fn redact_variable_default_values() {
+        assert_eq!(
+            redacted(
+                r#"mutation($password: String = "p", $name: String = "a") {
+                    login(password: $password, name: $name) { id }
+                }"#
+            ),
+            vec![
+                r#"$name="a""#,
+                r#"$password="<secret>""#,
+                r#"login.name=$name"#,
+                r#"login.password=$password"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn keep_variable_arguments() {
+        assert_eq!(
+            redacted(
+                r#"mutation($p: String) { login(password: $p, input: { token: $p }) { id } }"#
+            ),
+            vec![r#"login.input={token: $p}"#, r#"login.password=$p"#]
+        );
+    }
+}
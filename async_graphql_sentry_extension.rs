@@ -1,71 +1,742 @@
-use std::{fmt::Write, sync::Arc};
+use std::{
+    any::Any,
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+    hash::{DefaultHasher, Hash, Hasher},
+    panic::AssertUnwindSafe,
+    pin::Pin,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
 
 use async_graphql::{
-    PathSegment, Response,
-    extensions::{Extension, ExtensionContext, ExtensionFactory, NextExecute},
+    PathSegment, Response, ServerError, ServerResult, ValidationResult, Value, Variables,
+    extensions::{
+        Extension, ExtensionContext, ExtensionFactory, NextExecute, NextParseQuery,
+        NextPrepareRequest, NextRequest, NextResolve, NextSubscribe, NextValidation, ResolveInfo,
+    },
+    parser::types::{ExecutableDocument, OperationType},
+};
+use futures_util::{FutureExt, Stream, StreamExt, stream::BoxStream};
+use sentry::SentryFutureExt;
+
+use super::async_graphql_app_error::{error_code, is_client_error};
+use super::async_graphql_extensions_opentelemetry::{
+    is_credential, redact_document, serialize_variables,
 };
+use super::request_id::RequestId;
+
+const MAX_QUERY_LENGTH: usize = 8192;
+const MAX_VARIABLES_LENGTH: usize = 4096;
+const MAX_RATE_LIMIT_KEYS: usize = 1024;
+// キーを変えながら送られても、intervalあたりこの件数までしかSentryに送らない
+const MAX_REQUEST_ERRORS_PER_INTERVAL: usize = 100;
+
+/// Request `Data` に入っているユーザーをSentryのuserとtagに変換する
+pub trait SentryUser: Send + Sync + 'static {
+    fn id(&self) -> Option<String>;
+
+    fn email(&self) -> Option<String> {
+        None
+    }
+
+    fn segment(&self) -> Option<String> {
+        None
+    }
+
+    /// tenant IDなど
+    fn tags(&self) -> Vec<(String, String)> {
+        vec![]
+    }
+}
+
+type UserHook = Arc<
+    dyn Fn(&ExtensionContext<'_>) -> Option<(sentry::User, Vec<(String, String)>)> + Send + Sync,
+>;
+
+/// parse/validationエラーの間引き単位
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimitBy {
+    /// `GraphqlClient` がなければクエリのハッシュ
+    Client,
+    QueryHash,
+}
 
-pub struct Sentry;
+/// `request.data(GraphqlClient(...))` で渡すとクライアント単位で間引く
+#[derive(Debug, Clone)]
+pub struct GraphqlClient(pub String);
+
+#[derive(Clone)]
+pub struct Sentry {
+    user: Option<UserHook>,
+    resolve_breadcrumb_sample_rate: f32,
+    max_resolve_breadcrumbs: usize,
+    request_errors: Option<Arc<RateLimiter>>,
+}
+
+impl Default for Sentry {
+    fn default() -> Self {
+        Self {
+            user: None,
+            resolve_breadcrumb_sample_rate: 1.0,
+            max_resolve_breadcrumbs: 50,
+            request_errors: None,
+        }
+    }
+}
+
+impl Sentry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 成功したresolverのbreadcrumbを `sample_rate` の割合で、1リクエストあたり最大 `max` 件記録する
+    ///
+    /// エラーになったresolverは常に記録する
+    pub fn with_resolve_breadcrumbs(mut self, sample_rate: f32, max: usize) -> Self {
+        self.resolve_breadcrumb_sample_rate = sample_rate.clamp(0.0, 1.0);
+        self.max_resolve_breadcrumbs = max;
+        self
+    }
+
+    pub fn with_user<U: SentryUser>(mut self) -> Self {
+        self.user = Some(Arc::new(user_from_data::<U>));
+        self
+    }
+
+    /// parse/validationのエラーとpanicを送る。同じキーは `interval` に1回まで
+    pub fn with_request_errors(mut self, by: RateLimitBy, interval: Duration) -> Self {
+        self.request_errors = Some(Arc::new(RateLimiter {
+            by,
+            interval,
+            state: Default::default(),
+        }));
+        self
+    }
+}
+
+struct RateLimiter {
+    by: RateLimitBy,
+    interval: Duration,
+    state: Mutex<RateLimitState>,
+}
+
+#[derive(Default)]
+struct RateLimitState {
+    last_reported: HashMap<u64, Instant>,
+    window_started: Option<Instant>,
+    window_events: usize,
+}
+
+impl RateLimiter {
+    fn check(&self, key: u64) -> bool {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+
+        if !state
+            .window_started
+            .is_some_and(|started| now.duration_since(started) < self.interval)
+        {
+            state.window_started = Some(now);
+            state.window_events = 0;
+        }
+        if state.window_events >= MAX_REQUEST_ERRORS_PER_INTERVAL {
+            return false;
+        }
+
+        if let Some(reported) = state.last_reported.get(&key)
+            && now.duration_since(*reported) < self.interval
+        {
+            return false;
+        }
+        if state.last_reported.len() >= MAX_RATE_LIMIT_KEYS {
+            state
+                .last_reported
+                .retain(|_, reported| now.duration_since(*reported) < self.interval);
+        }
+        // 期限内のキーで埋まっていたら一番古いものを捨てる
+        if state.last_reported.len() >= MAX_RATE_LIMIT_KEYS
+            && let Some(oldest) = state
+                .last_reported
+                .iter()
+                .min_by_key(|(_, reported)| **reported)
+                .map(|(key, _)| *key)
+        {
+            state.last_reported.remove(&oldest);
+        }
+        state.last_reported.insert(key, now);
+        state.window_events += 1;
+        true
+    }
+}
 
 impl ExtensionFactory for Sentry {
     fn create(&self) -> Arc<dyn Extension> {
-        Arc::new(SentryExtension)
+        Arc::new(SentryExtension {
+            config: self.clone(),
+            query: Default::default(),
+            operation_name: Default::default(),
+            handled: Default::default(),
+            query_hash: AtomicU64::new(0),
+            resolved: AtomicUsize::new(0),
+            breadcrumbs: AtomicUsize::new(0),
+        })
+    }
+}
+
+fn user_from_data<U: SentryUser>(
+    ctx: &ExtensionContext<'_>,
+) -> Option<(sentry::User, Vec<(String, String)>)> {
+    let user = ctx.data_opt::<U>()?;
+    let mut other = sentry::protocol::Map::new();
+    if let Some(segment) = user.segment() {
+        other.insert(String::from("segment"), serde_json::Value::String(segment));
+    }
+    Some((
+        sentry::User {
+            id: user.id(),
+            email: user.email(),
+            other,
+            ..Default::default()
+        },
+        user.tags(),
+    ))
+}
+
+/// handlerで `request.data(sentry_request(&parts))` として渡すとイベントにrequestが付く
+pub fn sentry_request(parts: &axum::http::request::Parts) -> sentry::protocol::Request {
+    let header = |name: &str| {
+        parts
+            .headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+    };
+    let scheme = header("x-forwarded-proto").unwrap_or("http");
+    sentry::protocol::Request {
+        url: header("host").and_then(|host| {
+            format!("{}://{}{}", scheme, host, parts.uri.path())
+                .parse()
+                .ok()
+        }),
+        method: Some(parts.method.to_string()),
+        query_string: parts.uri.query().map(redact_query_string),
+        headers: parts
+            .headers
+            .iter()
+            .filter(|(name, _)| !is_sensitive_header(name.as_str()))
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+            .collect(),
+        ..Default::default()
+    }
+}
+
+const SENSITIVE_HEADERS: [&str; 4] = [
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+];
+
+// x-api-keyやx-auth-tokenなどは変数と同じルールで隠す
+fn is_sensitive_header(name: &str) -> bool {
+    SENSITIVE_HEADERS.contains(&name) || is_credential(name)
+}
+
+// `?access_token=...` のような値はheaderと同じルールで隠す
+fn redact_query_string(query: &str) -> String {
+    query
+        .split('&')
+        .map(|pair| match pair.split_once('=') {
+            Some((name, _)) if is_credential(&name.to_ascii_lowercase()) => {
+                format!("{}=<secret>", name)
+            }
+            _ => pair.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+struct QueryInfo {
+    query: String,
+    variables: String,
+    operations: Vec<(Option<String>, OperationType)>,
+}
+
+impl QueryInfo {
+    fn operation_type(&self, operation_name: Option<&str>) -> Option<OperationType> {
+        match operation_name {
+            Some(operation_name) => self
+                .operations
+                .iter()
+                .find(|(name, _)| name.as_deref() == Some(operation_name))
+                .map(|(_, ty)| *ty),
+            None => self.operations.first().map(|(_, ty)| *ty),
+        }
+    }
+}
+
+struct SentryExtension {
+    config: Sentry,
+    query: Arc<Mutex<Option<QueryInfo>>>,
+    // requestで指定されたoperation。ドキュメントに複数のoperationがあるときに使う
+    operation_name: Arc<Mutex<Option<String>>>,
+    // executeかvalidationで報告済み。execute_streamはquery/mutationでもsubscribeを通る
+    handled: Arc<AtomicBool>,
+    query_hash: AtomicU64,
+    resolved: AtomicUsize,
+    breadcrumbs: AtomicUsize,
+}
+
+impl SentryExtension {
+    // 乱数を使わず等間隔に間引く
+    fn sample_resolve(&self) -> bool {
+        let rate = self.config.resolve_breadcrumb_sample_rate;
+        let n = self.resolved.fetch_add(1, Ordering::Relaxed) as f32;
+        ((n + 1.0) * rate).floor() > (n * rate).floor()
+    }
+
+    fn record_success(&self) -> bool {
+        self.sample_resolve()
+            && self.breadcrumbs.fetch_add(1, Ordering::Relaxed)
+                < self.config.max_resolve_breadcrumbs
+    }
+
+    fn report_request_error(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        phase: &'static str,
+        level: sentry::Level,
+        messages: Vec<String>,
+    ) {
+        let Some(limiter) = self.config.request_errors.as_ref() else {
+            return;
+        };
+        let query_hash = self.query_hash.load(Ordering::Relaxed);
+        let client = ctx
+            .data_opt::<GraphqlClient>()
+            .map(|client| client.0.as_str());
+        let mut hasher = DefaultHasher::new();
+        phase.hash(&mut hasher);
+        match (limiter.by, client) {
+            (RateLimitBy::Client, Some(client)) => client.hash(&mut hasher),
+            _ => query_hash.hash(&mut hasher),
+        }
+        if !limiter.check(hasher.finish()) {
+            return;
+        }
+
+        let query = self.query.lock().unwrap();
+        let mut map = BTreeMap::new();
+        map.insert(String::from("phase"), serde_json::json!(phase));
+        map.insert(String::from("errors"), serde_json::json!(messages));
+        map.insert(String::from("client"), serde_json::json!(client));
+        map.insert(
+            String::from("query_hash"),
+            serde_json::json!(format!("{:016x}", query_hash)),
+        );
+        if let Some(query) = query.as_ref() {
+            map.insert(String::from("query"), serde_json::json!(query.query));
+            map.insert(
+                String::from("variables"),
+                serde_json::json!(query.variables),
+            );
+        }
+        let message = messages
+            .first()
+            .cloned()
+            .unwrap_or_else(|| String::from("GraphqlError"));
+        sentry::with_scope(
+            |scope| {
+                scope.set_tag("graphql.phase", phase);
+                scope.set_context("graphql", sentry::protocol::Context::Other(map));
+            },
+            || sentry::capture_message(&format!("graphql {}: {}", phase, message), level),
+        );
+    }
+
+    fn report_panic(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        phase: &'static str,
+        panic: &(dyn Any + Send),
+    ) {
+        self.report_request_error(ctx, phase, sentry::Level::Fatal, vec![panic_message(panic)]);
     }
 }
 
-struct SentryExtension;
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        String::from("panic")
+    }
+}
 
 #[async_graphql::async_trait::async_trait]
 impl Extension for SentryExtension {
+    async fn request(&self, ctx: &ExtensionContext<'_>, next: NextRequest<'_>) -> Response {
+        // 共有hubだと並行リクエストのcontext, tag, breadcrumbが混ざるのでリクエストごとに分ける
+        next.run(ctx)
+            .bind_hub(Arc::new(sentry::Hub::new_from_top(sentry::Hub::current())))
+            .await
+    }
+
+    async fn prepare_request(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        request: async_graphql::Request,
+        next: NextPrepareRequest<'_>,
+    ) -> ServerResult<async_graphql::Request> {
+        *self.operation_name.lock().unwrap() = request.operation_name.clone();
+        next.run(ctx, request).await
+    }
+
+    fn subscribe<'s>(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        stream: BoxStream<'s, Response>,
+        next: NextSubscribe<'_>,
+    ) -> BoxStream<'s, Response> {
+        // コネクションのscopeと混ざらないようにsubscriptionごとにhubを分ける
+        Box::pin(SubscriptionStream {
+            inner: next.run(ctx, stream),
+            hub: Arc::new(sentry::Hub::new_from_top(sentry::Hub::current())),
+            query: self.query.clone(),
+            operation_name: self.operation_name.clone(),
+            handled: self.handled.clone(),
+            started: Instant::now(),
+            events: 0,
+        })
+    }
+
+    async fn parse_query(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        query: &str,
+        variables: &Variables,
+        next: NextParseQuery<'_>,
+    ) -> ServerResult<ExecutableDocument> {
+        let mut hasher = DefaultHasher::new();
+        query.hash(&mut hasher);
+        self.query_hash.store(hasher.finish(), Ordering::Relaxed);
+
+        let res = match AssertUnwindSafe(next.run(ctx, query, variables))
+            .catch_unwind()
+            .await
+        {
+            Ok(res) => res,
+            Err(panic) => {
+                self.report_panic(ctx, "parse", &*panic);
+                std::panic::resume_unwind(panic);
+            }
+        };
+        let doc = match res {
+            Ok(doc) => doc,
+            Err(err) => {
+                self.report_request_error(
+                    ctx,
+                    "parse",
+                    sentry::Level::Warning,
+                    vec![err.message.clone()],
+                );
+                return Err(err);
+            }
+        };
+        // 引数のリテラルはredact_documentで、変数はserialize_variablesで、名前から判断して隠す
+        let info = QueryInfo {
+            query: truncate(
+                ctx.stringify_execute_doc(&redact_document(&doc), variables),
+                MAX_QUERY_LENGTH,
+            ),
+            variables: truncate(serialize_variables(variables), MAX_VARIABLES_LENGTH),
+            operations: doc
+                .operations
+                .iter()
+                .map(|(name, op)| (name.map(|name| name.to_string()), op.node.ty))
+                .collect(),
+        };
+        *self.query.lock().unwrap() = Some(info);
+        Ok(doc)
+    }
+
+    async fn validation(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        next: NextValidation<'_>,
+    ) -> Result<ValidationResult, Vec<ServerError>> {
+        let res = match AssertUnwindSafe(next.run(ctx)).catch_unwind().await {
+            Ok(res) => res,
+            Err(panic) => {
+                self.report_panic(ctx, "validation", &*panic);
+                std::panic::resume_unwind(panic);
+            }
+        };
+        if let Err(errors) = &res {
+            self.handled.store(true, Ordering::Relaxed);
+            self.report_request_error(
+                ctx,
+                "validation",
+                sentry::Level::Warning,
+                errors.iter().map(|err| err.message.clone()).collect(),
+            );
+        }
+        res
+    }
+
     async fn execute(
         &self,
         ctx: &ExtensionContext<'_>,
         operation_name: Option<&str>,
         next: NextExecute<'_>,
     ) -> Response {
-        super::sentry_otel::set_otel_sentry_scope();
+        // event processorなどをoperationのscopeに閉じ込めるため、requestのhubとは別にする
+        let hub = Arc::new(sentry::Hub::new_from_top(sentry::Hub::current()));
+        sentry::Hub::run(hub.clone(), super::sentry_otel::set_otel_sentry_scope);
 
-        let resp = next.run(ctx, operation_name).await;
+        let user = self.config.user.as_ref().and_then(|user| user(ctx));
+        let request = ctx.data_opt::<sentry::protocol::Request>().cloned();
+        // WebSocketのタスクではtask localが無いのでsessionのdataも見る
+        let request_id = RequestId::current().or_else(|| ctx.data_opt::<RequestId>().cloned());
+        hub.configure_scope(|scope| {
+            if let Some(request_id) = request_id {
+                scope.set_tag("request_id", request_id);
+            }
+            if let Some((user, tags)) = user {
+                scope.set_user(Some(user));
+                for (key, value) in tags {
+                    scope.set_tag(&key, value);
+                }
+            }
+            if let Some(request) = request {
+                scope.add_event_processor(move |mut event| {
+                    if event.request.is_none() {
+                        event.request = Some(request.clone());
+                    }
+                    Some(event)
+                });
+            }
+        });
+
+        self.handled.store(true, Ordering::Relaxed);
+        let resp = next.run(ctx, operation_name).bind_hub(hub.clone()).await;
 
         if resp.is_err() {
-            let mut error_message = None;
-            let mut paths = vec![];
-            let mut errors = vec![];
-            for err in &resp.errors {
-                if !err.path.is_empty() {
-                    let mut path = String::new();
-                    for (idx, s) in err.path.iter().enumerate() {
-                        if idx > 0 {
-                            path.push('.');
-                        }
-                        match s {
-                            PathSegment::Index(idx) => {
-                                let _ = write!(&mut path, "{}", idx);
-                            }
-                            PathSegment::Field(name) => {
-                                let _ = write!(&mut path, "{}", name);
-                            }
-                        }
+            let query = self.query.lock().unwrap();
+            sentry::Hub::run(hub, || {
+                report_response(&resp, query.as_ref(), operation_name, Default::default())
+            });
+        }
+        resp
+    }
+
+    async fn resolve(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        info: ResolveInfo<'_>,
+        next: NextResolve<'_>,
+    ) -> ServerResult<Option<Value>> {
+        if info.is_for_introspection {
+            return next.run(ctx, info).await;
+        }
+
+        let path_node = info.path_node;
+        let parent_type = info.parent_type;
+        let return_type = info.return_type;
+        let start = Instant::now();
+        let res = next.run(ctx, info).await;
+        let duration_ms = start.elapsed().as_secs_f64() * 1000.0;
+
+        let (level, message) = match &res {
+            Ok(_) if self.record_success() => (sentry::Level::Info, None),
+            Ok(_) => return res,
+            Err(err) => (sentry::Level::Error, Some(err.message.clone())),
+        };
+        let mut data = sentry::protocol::Map::new();
+        data.insert(
+            String::from("path"),
+            serde_json::json!(path_node.to_string()),
+        );
+        data.insert(String::from("parent_type"), serde_json::json!(parent_type));
+        data.insert(String::from("return_type"), serde_json::json!(return_type));
+        data.insert(String::from("duration_ms"), serde_json::json!(duration_ms));
+        if let Some(message) = message.as_ref() {
+            data.insert(String::from("error"), serde_json::json!(message));
+        }
+        sentry::add_breadcrumb(sentry::Breadcrumb {
+            category: Some(String::from("graphql.resolve")),
+            message: Some(message.unwrap_or_else(|| path_node.to_string())),
+            level,
+            data,
+            ..Default::default()
+        });
+        res
+    }
+}
+
+struct SubscriptionStream<'s> {
+    inner: BoxStream<'s, Response>,
+    hub: Arc<sentry::Hub>,
+    query: Arc<Mutex<Option<QueryInfo>>>,
+    operation_name: Arc<Mutex<Option<String>>>,
+    handled: Arc<AtomicBool>,
+    started: Instant,
+    events: usize,
+}
+
+impl SubscriptionStream<'_> {
+    fn subscription_context(&self) -> BTreeMap<String, serde_json::Value> {
+        let operation_name = self.operation_name.lock().unwrap();
+        let mut map = BTreeMap::new();
+        map.insert(
+            String::from("subscription"),
+            serde_json::json!(operation_name.as_deref().unwrap_or("<anonymous>")),
+        );
+        map.insert(String::from("event_count"), serde_json::json!(self.events));
+        map.insert(
+            String::from("connection_duration_ms"),
+            serde_json::json!(self.started.elapsed().as_millis() as u64),
+        );
+        map
+    }
+}
+
+impl Stream for SubscriptionStream<'_> {
+    type Item = Response;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Response>> {
+        let this = &mut *self;
+        sentry::Hub::run(this.hub.clone(), || {
+            let poll =
+                match std::panic::catch_unwind(AssertUnwindSafe(|| this.inner.poll_next_unpin(cx)))
+                {
+                    Ok(poll) => poll,
+                    Err(panic) => {
+                        let map = this.subscription_context();
+                        sentry::configure_scope(|scope| {
+                            scope.set_context("graphql", sentry::protocol::Context::Other(map));
+                        });
+                        tracing::error!(
+                            "graphql subscription panicked: {}",
+                            panic_message(&*panic)
+                        );
+                        std::panic::resume_unwind(panic);
                     }
-                    paths.push(path);
-                    errors.push(err.message.clone());
+                };
+            if let Poll::Ready(Some(resp)) = &poll {
+                this.events += 1;
+                // subscription以外はexecuteとreport_request_errorに任せる
+                if resp.is_err() && !this.handled.load(Ordering::Relaxed) {
+                    let map = this.subscription_context();
+                    let query = this.query.lock().unwrap();
+                    let operation_name = this.operation_name.lock().unwrap();
+                    if let Some(query) = query.as_ref()
+                        && query.operation_type(operation_name.as_deref())
+                            == Some(OperationType::Subscription)
+                    {
+                        super::sentry_otel::set_otel_sentry_scope();
+                        report_response(resp, Some(query), operation_name.as_deref(), map);
+                    }
+                }
+            }
+            poll
+        })
+    }
+}
+
+fn report_response(
+    resp: &Response,
+    query: Option<&QueryInfo>,
+    operation_name: Option<&str>,
+    mut map: BTreeMap<String, serde_json::Value>,
+) {
+    let mut error_message = None;
+    let mut paths = vec![];
+    let mut errors = vec![];
+    let mut codes = vec![];
+    let mut server_error = false;
+    for err in &resp.errors {
+        if let Some(code) = error_code(err.extensions.as_ref()) {
+            codes.push(code);
+        }
+        if !is_client_error(err.extensions.as_ref()) {
+            server_error = true;
+        }
+        if !err.path.is_empty() {
+            let mut path = String::new();
+            for (idx, s) in err.path.iter().enumerate() {
+                if idx > 0 {
+                    path.push('.');
                 }
-                if error_message.is_none() {
-                    error_message = Some(err.message.clone());
+                match s {
+                    PathSegment::Index(idx) => {
+                        let _ = write!(&mut path, "{}", idx);
+                    }
+                    PathSegment::Field(name) => {
+                        let _ = write!(&mut path, "{}", name);
+                    }
                 }
             }
-            sentry::configure_scope(|scope| {
-                let mut map = std::collections::BTreeMap::new();
-                map.insert(String::from("path"), serde_json::json!(paths));
-                map.insert(String::from("errors"), serde_json::json!(errors));
+            paths.push(path);
+            errors.push(err.message.clone());
+        }
+        if error_message.is_none() {
+            error_message = Some(err.message.clone());
+        }
+    }
+    sentry::configure_scope(|scope| {
+        map.insert(String::from("path"), serde_json::json!(paths));
+        map.insert(String::from("errors"), serde_json::json!(errors));
+        map.insert(String::from("codes"), serde_json::json!(codes));
+        map.insert(
+            String::from("operation_name"),
+            serde_json::json!(operation_name),
+        );
+        if let Some(query) = query {
+            map.insert(
+                String::from("operation_type"),
+                serde_json::json!(
+                    query
+                        .operation_type(operation_name)
+                        .map(|ty| ty.to_string())
+                ),
+            );
+            map.insert(String::from("query"), serde_json::json!(query.query));
+            map.insert(
+                String::from("variables"),
+                serde_json::json!(query.variables),
+            );
+        }
 
-                scope.set_context("graphql", sentry::protocol::Context::Other(map));
-            });
+        if let Some(code) = codes.first() {
+            scope.set_tag("graphql.error_code", code);
+        }
+        scope.set_context("graphql", sentry::protocol::Context::Other(map));
+    });
 
-            let error_message = error_message.unwrap_or("GraphqlError".to_string());
-            // sentry::capture_message(&error_message, sentry::Level::Error);
-            tracing::error!("{}", error_message);
+    let error_message = error_message.unwrap_or("GraphqlError".to_string());
+    // sentry::capture_message(&error_message, sentry::Level::Error);
+    if server_error {
+        tracing::error!("{}", error_message);
+    } else {
+        // 4xxだけならeventにせずbreadcrumbに留める
+        tracing::warn!("{}", error_message);
+    }
+}
+
+fn truncate(mut value: String, max_len: usize) -> String {
+    if value.len() > max_len {
+        let mut end = max_len;
+        while !value.is_char_boundary(end) {
+            end -= 1;
         }
-        resp
+        value.truncate(end);
+        value.push_str("...<truncated>");
     }
+    value
 }
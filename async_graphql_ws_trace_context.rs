@@ -0,0 +1,117 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use async_graphql::{Data, Executor, Request, Response, Value};
+use futures_util::stream::BoxStream;
+use opentelemetry::trace::FutureExt;
+
+use super::parent_trace_context::{
+    ParentTraceContext, checked_header, is_propagation_field, propagation_fields,
+};
+
+// WebSocketのupgradeではheaderを付けられないので、payloadに `traceparent` などを入れてもらう
+// `{"traceparent": "..."}` と `{"headers": {"traceparent": "..."}}` のどちらでもよい
+
+/// `connection_init` のpayloadから `ParentTraceContext` を取り出してsessionのdataにする
+///
+/// ```ignore
+/// GraphQLWebSocket::new(stream, TracedExecutor::new(schema), protocol)
+///     .on_connection_init(|payload| async move { connection_init_data(payload) })
+/// ```
+pub fn connection_init_data(payload: serde_json::Value) -> async_graphql::Result<Data> {
+    let mut data = Data::default();
+    if let Some(parent) = parent_from_payload(&payload) {
+        data.insert(parent);
+    }
+    Ok(data)
+}
+
+pub fn parent_from_payload(payload: &serde_json::Value) -> Option<ParentTraceContext> {
+    let payload = payload.as_object()?;
+    let values = payload
+        .iter()
+        .chain(
+            payload
+                .get("headers")
+                .and_then(|headers| headers.as_object())
+                .into_iter()
+                .flatten(),
+        )
+        .filter_map(|(name, value)| Some((name.as_str(), value.as_str()?)));
+    checked_parent(values)
+}
+
+/// subscribeメッセージの `extensions` から取り出す
+pub fn parent_from_extensions(extensions: &HashMap<String, Value>) -> Option<ParentTraceContext> {
+    let nested = match extensions.get("headers") {
+        Some(Value::Object(nested)) => Some(nested),
+        _ => None,
+    };
+    let values = extensions
+        .iter()
+        .map(|(name, value)| (name.as_str(), value))
+        .chain(
+            nested
+                .into_iter()
+                .flatten()
+                .map(|(name, value)| (name.as_str(), value)),
+        )
+        .filter_map(|(name, value)| match value {
+            Value::String(value) => Some((name, value.as_str())),
+            _ => None,
+        });
+    checked_parent(values)
+}
+
+// headerと同じように検証し、不正な値はdebugログとmetricを出して捨てる
+fn checked_parent<'a>(
+    values: impl Iterator<Item = (&'a str, &'a str)>,
+) -> Option<ParentTraceContext> {
+    let fields = propagation_fields();
+    let headers = values.filter_map(|(name, value)| {
+        let name = name.to_ascii_lowercase();
+        if !is_propagation_field(&fields, &name) {
+            return None;
+        }
+        let value = checked_header(&name, value.as_bytes())?.to_string();
+        Some((name, value))
+    });
+    let parent = ParentTraceContext::from_headers(headers);
+    (!parent.headers().is_empty()).then_some(parent)
+}
+
+/// operationごとの `extensions` にtrace contextがあれば、それをcurrent contextにして実行するExecutor
+///
+/// `subscribe` hookは `execute_stream` の中で同期的に呼ばれ、query dataが付く前に動くので
+/// dataではなくcurrent contextで渡す。`connection_init` の値より優先される
+#[derive(Clone)]
+pub struct TracedExecutor<E> {
+    inner: E,
+}
+
+impl<E: Executor> TracedExecutor<E> {
+    pub fn new(inner: E) -> Self {
+        Self { inner }
+    }
+}
+
+fn parent_context(request: &Request) -> Option<opentelemetry::Context> {
+    parent_from_extensions(&request.extensions).map(|parent| parent.get())
+}
+
+// execute_batchはデフォルト実装がexecuteを呼ぶ
+impl<E: Executor> Executor for TracedExecutor<E> {
+    fn execute(&self, request: Request) -> impl Future<Output = Response> + Send {
+        let cx = parent_context(&request).unwrap_or_else(opentelemetry::Context::current);
+        self.inner.execute(request).with_context(cx)
+    }
+
+    fn execute_stream(
+        &self,
+        request: Request,
+        session_data: Option<Arc<Data>>,
+    ) -> BoxStream<'static, Response> {
+        let _guard = parent_context(&request).map(|cx| cx.attach());
+        self.inner.execute_stream(request, session_data)
+    }
+}
@@ -1,18 +1,543 @@
-use axum::http::StatusCode;
+use std::error::Error as StdError;
+use std::fmt::{Debug, Display};
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use axum::extract::Request;
+use axum::http::{HeaderName, HeaderValue, StatusCode, header};
+use axum::middleware::Next;
 use axum::response::{IntoResponse, Response};
+use opentelemetry::trace::{Status, TraceContextExt};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use super::axum_error_messages::{
+    DEFAULT_LANGUAGE, INTERNAL_ERROR_DETAIL_CODE, message, negotiate_language,
+};
+use super::parent_trace_context::current_context;
+use super::request_id::RequestId;
+
+const PROBLEM_TYPE_PREFIX: &str = "urn:problem-type:";
+const PROBLEM_JSON: &str = "application/problem+json";
+
+const TRACE_ID_HEADER: HeaderName = HeaderName::from_static("x-trace-id");
+const SENTRY_EVENT_ID_HEADER: HeaderName = HeaderName::from_static("x-sentry-event-id");
+
+/// AppErrorのログはSentryに直接送るので、sentry_tracingではこのtargetを無視する
+pub const LOG_TARGET: &str = "app_error";
+
+static EXPOSE_INTERNAL_ERRORS: AtomicBool = AtomicBool::new(cfg!(debug_assertions));
+
+/// 5xxのエラー内容をレスポンスに含めるか。デフォルトはdebugビルドのみ含める
+pub fn set_expose_internal_errors(expose: bool) {
+    EXPOSE_INTERNAL_ERRORS.store(expose, Ordering::Relaxed);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorKind {
+    Internal,
+    NotFound,
+    Unauthorized,
+    Forbidden,
+    Conflict,
+    Validation,
+    RateLimited,
+    Upstream,
+}
+
+impl ErrorKind {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ErrorKind::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorKind::NotFound => StatusCode::NOT_FOUND,
+            ErrorKind::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorKind::Forbidden => StatusCode::FORBIDDEN,
+            ErrorKind::Conflict => StatusCode::CONFLICT,
+            ErrorKind::Validation => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorKind::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            ErrorKind::Upstream => StatusCode::BAD_GATEWAY,
+        }
+    }
+
+    // クライアントが分岐に使うので変更しないこと
+    pub fn code(&self) -> &'static str {
+        match self {
+            ErrorKind::Internal => "internal_error",
+            ErrorKind::NotFound => "not_found",
+            ErrorKind::Unauthorized => "unauthorized",
+            ErrorKind::Forbidden => "forbidden",
+            ErrorKind::Conflict => "conflict",
+            ErrorKind::Validation => "validation_failed",
+            ErrorKind::RateLimited => "rate_limited",
+            ErrorKind::Upstream => "upstream_failure",
+        }
+    }
+
+    pub fn from_code(code: &str) -> Option<Self> {
+        [
+            ErrorKind::Internal,
+            ErrorKind::NotFound,
+            ErrorKind::Unauthorized,
+            ErrorKind::Forbidden,
+            ErrorKind::Conflict,
+            ErrorKind::Validation,
+            ErrorKind::RateLimited,
+            ErrorKind::Upstream,
+        ]
+        .into_iter()
+        .find(|kind| kind.code() == code)
+    }
+}
 
 // Make our own error that wraps `anyhow::Error`.
-pub struct AppError(anyhow::Error);
+#[derive(Debug)]
+pub struct AppError {
+    kind: ErrorKind,
+    status: StatusCode,
+    error: anyhow::Error,
+    extensions: serde_json::Map<String, serde_json::Value>,
+    field_errors: Vec<FieldError>,
+    message_code: Option<&'static str>,
+    params: Vec<(String, String)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldError {
+    pub field: Option<String>,
+    pub code: String,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new(field: Option<&str>, code: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            field: field.map(|field| field.to_string()),
+            code: code.into(),
+            message: message.into(),
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "field": self.field,
+            "code": self.code,
+            "message": self.message,
+        })
+    }
+}
+
+impl AppError {
+    pub fn new(kind: ErrorKind, err: impl Into<anyhow::Error>) -> Self {
+        Self {
+            kind,
+            status: kind.status(),
+            error: err.into(),
+            extensions: Default::default(),
+            field_errors: vec![],
+            message_code: None,
+            params: vec![],
+        }
+    }
+
+    pub fn msg<M>(kind: ErrorKind, message: M) -> Self
+    where
+        M: Display + Debug + Send + Sync + 'static,
+    {
+        Self::new(kind, anyhow::Error::msg(message))
+    }
+
+    /// メッセージはaxum_error_messagesのカタログから `Accept-Language` に合わせて選ばれる
+    pub fn coded(kind: ErrorKind, message_code: &'static str) -> Self {
+        let mut err = Self::msg(kind, message_code);
+        err.message_code = Some(message_code);
+        err
+    }
+
+    pub fn internal<M: Display + Debug + Send + Sync + 'static>(message: M) -> Self {
+        Self::msg(ErrorKind::Internal, message)
+    }
+
+    pub fn not_found<M: Display + Debug + Send + Sync + 'static>(message: M) -> Self {
+        Self::msg(ErrorKind::NotFound, message)
+    }
+
+    pub fn unauthorized<M: Display + Debug + Send + Sync + 'static>(message: M) -> Self {
+        Self::msg(ErrorKind::Unauthorized, message)
+    }
+
+    pub fn forbidden<M: Display + Debug + Send + Sync + 'static>(message: M) -> Self {
+        Self::msg(ErrorKind::Forbidden, message)
+    }
+
+    pub fn conflict<M: Display + Debug + Send + Sync + 'static>(message: M) -> Self {
+        Self::msg(ErrorKind::Conflict, message)
+    }
+
+    pub fn validation<M: Display + Debug + Send + Sync + 'static>(message: M) -> Self {
+        Self::msg(ErrorKind::Validation, message)
+    }
+
+    /// フィールドごとのエラーを持つ422
+    pub fn invalid_fields(field_errors: Vec<FieldError>) -> Self {
+        let message = field_errors
+            .iter()
+            .map(|err| match err.field.as_ref() {
+                Some(field) => format!("{}: {}", field, err.message),
+                None => err.message.clone(),
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        let mut err = Self::msg(ErrorKind::Validation, message);
+        err.field_errors = field_errors;
+        err
+    }
+
+    pub fn rate_limited<M: Display + Debug + Send + Sync + 'static>(message: M) -> Self {
+        Self::msg(ErrorKind::RateLimited, message)
+    }
+
+    pub fn upstream<M: Display + Debug + Send + Sync + 'static>(message: M) -> Self {
+        Self::msg(ErrorKind::Upstream, message)
+    }
+
+    /// kindのデフォルトと違うstatusを返したいとき
+    pub fn with_status(mut self, status: StatusCode) -> Self {
+        self.status = status;
+        self
+    }
+
+    /// problem+jsonのextension memberとして返す
+    pub fn with_extension(
+        mut self,
+        key: impl Into<String>,
+        value: impl Into<serde_json::Value>,
+    ) -> Self {
+        self.extensions.insert(key.into(), value.into());
+        self
+    }
+
+    pub fn with_param(mut self, name: impl Into<String>, value: impl Display) -> Self {
+        self.params.push((name.into(), value.to_string()));
+        self
+    }
+
+    pub fn with_field_error(mut self, field_error: FieldError) -> Self {
+        self.field_errors.push(field_error);
+        self
+    }
+
+    // mapperが作ったエラーに元のエラーをつなげる。chainに同じメッセージがあればそのまま置き換える
+    fn with_source(mut self, source: anyhow::Error) -> Self {
+        let message = self.error.to_string();
+        self.error = if source.chain().any(|err| err.to_string() == message) {
+            source
+        } else {
+            source.context(message)
+        };
+        self
+    }
+
+    pub fn field_errors(&self) -> &[FieldError] {
+        &self.field_errors
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    pub fn code(&self) -> &'static str {
+        self.kind.code()
+    }
+
+    pub fn error(&self) -> &anyhow::Error {
+        &self.error
+    }
+
+    pub fn into_inner(self) -> anyhow::Error {
+        self.error
+    }
+
+    /// クライアントに返してよいメッセージ
+    pub fn public_message(&self) -> String {
+        match self.detail_code() {
+            Some(code) => {
+                message(DEFAULT_LANGUAGE, code, &self.params).unwrap_or_else(|| code.to_string())
+            }
+            None => self.error.to_string(),
+        }
+    }
+
+    fn detail_code(&self) -> Option<&'static str> {
+        if self.status.is_server_error() && !EXPOSE_INTERNAL_ERRORS.load(Ordering::Relaxed) {
+            Some(INTERNAL_ERROR_DETAIL_CODE)
+        } else {
+            self.message_code
+        }
+    }
+
+    pub fn problem(&self) -> Problem {
+        let mut extensions = self.extensions.clone();
+        extensions.insert(String::from("code"), self.code().into());
+        if let Some(trace_id) = current_trace_id() {
+            extensions.insert(String::from("traceId"), trace_id.into());
+        }
+        if !self.field_errors.is_empty() {
+            extensions.insert(
+                String::from("errors"),
+                self.field_errors.iter().map(FieldError::to_json).collect(),
+            );
+        }
+        let mut problem = Problem {
+            code: self.code(),
+            title: self
+                .status
+                .canonical_reason()
+                .unwrap_or("Error")
+                .to_string(),
+            status: self.status,
+            detail: Some(self.error.to_string()),
+            instance: None,
+            extensions,
+            // with_statusで変えたときはkindのタイトルが合わないのでreason phraseのまま
+            title_code: (self.status == self.kind.status()).then_some(self.code()),
+            detail_code: self.detail_code(),
+            params: self.params.clone(),
+            language: None,
+        };
+        problem.localize(DEFAULT_LANGUAGE);
+        problem
+    }
+}
+
+/// RFC 9457 problem details
+#[derive(Debug, Clone)]
+pub struct Problem {
+    pub code: &'static str,
+    pub title: String,
+    pub status: StatusCode,
+    pub detail: Option<String>,
+    pub instance: Option<String>,
+    pub extensions: serde_json::Map<String, serde_json::Value>,
+    pub title_code: Option<&'static str>,
+    pub detail_code: Option<&'static str>,
+    pub params: Vec<(String, String)>,
+    pub language: Option<&'static str>,
+}
+
+impl Problem {
+    pub fn localize(&mut self, language: &'static str) {
+        if let Some(title) = self
+            .title_code
+            .and_then(|code| message(language, code, &self.params))
+        {
+            self.title = title;
+        }
+        if let Some(detail) = self
+            .detail_code
+            .and_then(|code| message(language, code, &self.params))
+        {
+            self.detail = Some(detail);
+        }
+        self.language = Some(language);
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut map = self.extensions.clone();
+        map.insert(
+            String::from("type"),
+            format!("{}{}", PROBLEM_TYPE_PREFIX, self.code).into(),
+        );
+        map.insert(String::from("title"), self.title.clone().into());
+        map.insert(String::from("status"), self.status.as_u16().into());
+        if let Some(detail) = self.detail.as_ref() {
+            map.insert(String::from("detail"), detail.clone().into());
+        }
+        if let Some(instance) = self.instance.as_ref() {
+            map.insert(String::from("instance"), instance.clone().into());
+        }
+        serde_json::Value::Object(map)
+    }
+
+    pub fn to_text(&self) -> String {
+        match self.detail.as_ref() {
+            Some(detail) => format!("{}: {}", self.title, detail),
+            None => self.title.clone(),
+        }
+    }
+
+    fn into_response_with(self, text: bool) -> Response {
+        let (content_type, body) = if text {
+            ("text/plain; charset=utf-8", self.to_text())
+        } else {
+            (PROBLEM_JSON, self.to_json().to_string())
+        };
+        let mut response = (
+            self.status,
+            [(header::CONTENT_TYPE, HeaderValue::from_static(content_type))],
+            body,
+        )
+            .into_response();
+        if let Some(language) = self
+            .language
+            .and_then(|language| HeaderValue::from_str(language).ok())
+        {
+            response
+                .headers_mut()
+                .insert(header::CONTENT_LANGUAGE, language);
+        }
+        response.extensions_mut().insert(self);
+        response
+    }
+}
 
 // Tell axum how to convert `AppError` into a response.
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Something went wrong: {}", self.0),
-        )
-            .into_response()
+        let mut problem = self.problem();
+        let request_id = RequestId::current();
+        if let Some(request_id) = &request_id {
+            problem
+                .extensions
+                .insert(String::from("requestId"), request_id.to_string().into());
+        }
+        let mut event_id = None;
+        if self.status.is_server_error() {
+            let (error_id, sentry_event_id) = self.report(request_id.as_ref());
+            event_id = sentry_event_id;
+            problem
+                .extensions
+                .insert(String::from("errorId"), error_id.to_string().into());
+        } else {
+            tracing::info!(
+                target: LOG_TARGET,
+                status = self.status.as_u16(),
+                code = self.code(),
+                "{:#}",
+                self.error
+            );
+        }
+
+        let mut response = problem.into_response_with(false);
+        let headers = response.headers_mut();
+        if let Some(trace_id) = current_trace_id().and_then(|id| HeaderValue::from_str(&id).ok()) {
+            headers.insert(TRACE_ID_HEADER, trace_id);
+        }
+        if let Some(event_id) = event_id.and_then(|id| HeaderValue::from_str(&id.to_string()).ok())
+        {
+            headers.insert(SENTRY_EVENT_ID_HEADER, event_id);
+        }
+        response
+    }
+}
+
+impl AppError {
+    // error IDとSentryのevent IDを返す。Sentryが無効ならerror IDはログとの突き合わせ用に生成する
+    pub(crate) fn report(
+        &self,
+        request_id: Option<&RequestId>,
+    ) -> (sentry::types::Uuid, Option<sentry::types::Uuid>) {
+        let event_id = sentry::with_scope(
+            |scope| {
+                if let Some(request_id) = request_id {
+                    scope.set_tag("request_id", request_id);
+                }
+            },
+            || sentry::integrations::anyhow::capture_anyhow(&self.error),
+        );
+        let event_id = (!event_id.is_nil()).then_some(event_id);
+        let error_id = event_id.unwrap_or_else(sentry::types::Uuid::new_v4);
+
+        // handlerのcurrent contextはtracingのspanから作ったnon-recordingなspanなので、
+        // tracingのspan経由でstatusを付ける。errorフィールドはexception eventになる
+        tracing::Span::current().set_status(Status::error(self.error.to_string()));
+        // resolverなどOTelのspanが直接付いているとき
+        let otel_context = opentelemetry::Context::current();
+        let span = otel_context.span();
+        if span.is_recording() {
+            span.record_error(&*self.error);
+            span.set_status(Status::error(self.error.to_string()));
+        }
+
+        let error: &(dyn StdError + 'static) = self.error.as_ref();
+        tracing::error!(
+            target: LOG_TARGET,
+            error,
+            error_id = %error_id,
+            status = self.status.as_u16(),
+            code = self.code(),
+            "{:?}",
+            self.error
+        );
+        (error_id, event_id)
+    }
+}
+
+fn current_trace_id() -> Option<String> {
+    let span_context = current_context().span().span_context().clone();
+    span_context
+        .is_valid()
+        .then(|| span_context.trace_id().to_string())
+}
+
+/// `Router::layer(axum::middleware::from_fn(problem_details))` で使う
+///
+/// `Accept` を見てブラウザにはtext/plainで返し、`instance` にリクエストのパスを入れる。
+/// メッセージは `Accept-Language` の言語にする
+pub async fn problem_details(request: Request, next: Next) -> Response {
+    let text = prefers_text(
+        request
+            .headers()
+            .get(header::ACCEPT)
+            .and_then(|value| value.to_str().ok()),
+    );
+    let language = negotiate_language(
+        request
+            .headers()
+            .get(header::ACCEPT_LANGUAGE)
+            .and_then(|value| value.to_str().ok()),
+    );
+    let path = request.uri().path().to_string();
+
+    let response = next.run(request).await;
+    let (mut parts, body) = response.into_parts();
+    let Some(mut problem) = parts.extensions.remove::<Problem>() else {
+        return Response::from_parts(parts, body);
+    };
+    if problem.instance.is_none() {
+        problem.instance = Some(path);
     }
+    problem.localize(language);
+
+    let (new_parts, new_body) = problem.into_response_with(text).into_parts();
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.extend(new_parts.headers);
+    parts.extensions.extend(new_parts.extensions);
+    Response::from_parts(parts, new_body)
+}
+
+fn prefers_text(accept: Option<&str>) -> bool {
+    let Some(accept) = accept else {
+        return false;
+    };
+    let mut json = 0.0f32;
+    let mut text = 0.0f32;
+    for range in accept.split(',') {
+        let mut params = range.split(';');
+        let media_type = params.next().unwrap_or_default().trim();
+        let q = params
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        match media_type {
+            PROBLEM_JSON | "application/json" | "application/*" | "*/*" => json = json.max(q),
+            "text/html" | "text/plain" | "text/*" => text = text.max(q),
+            _ => {}
+        }
+    }
+    text > json
 }
 
 // This enables using `?` on functions that return `Result<_, anyhow::Error>` to turn them into
@@ -22,6 +547,92 @@ where
     E: Into<anyhow::Error>,
 {
     fn from(err: E) -> Self {
-        Self(err.into())
+        let error = err.into();
+        match classify(&error) {
+            Some(mapped) => mapped.with_source(error),
+            None => Self::new(ErrorKind::Internal, error),
+        }
+    }
+}
+
+/// 独自のエラー型に実装して `register_error_kind::<E>()` しておくと `?` で正しいkindになる
+pub trait IntoAppErrorKind: StdError + Send + Sync + 'static {
+    fn app_error_kind(&self) -> ErrorKind;
+}
+
+type ErrorMapper = Box<dyn Fn(&(dyn StdError + 'static)) -> Option<AppError> + Send + Sync>;
+
+static ERROR_MAPPERS: RwLock<Vec<ErrorMapper>> = RwLock::new(Vec::new());
+
+pub fn register_error_kind<E: IntoAppErrorKind>() {
+    register_error_mapper(|err: &E| Some(AppError::msg(err.app_error_kind(), err.to_string())));
+}
+
+/// 外部crateのエラー型を `AppError` に変換する方法を登録する。起動時に呼ぶ
+///
+/// 返した `AppError` のkind, status, message code, field errorなどが使われ、元のエラーはsourceに残る
+///
+/// ```ignore
+/// register_error_mapper(|err: &sqlx::Error| {
+///     matches!(err, sqlx::Error::RowNotFound).then(|| AppError::coded(ErrorKind::NotFound, "not_found"))
+/// });
+/// register_error_mapper(|err: &validator::ValidationErrors| {
+///     Some(AppError::invalid_fields(
+///         err.field_errors()
+///             .into_iter()
+///             .flat_map(|(field, errors)| {
+///                 errors.iter().map(move |e| FieldError::new(Some(&field), e.code.clone(), e.to_string()))
+///             })
+///             .collect(),
+///     ))
+/// });
+/// ```
+pub fn register_error_mapper<E, F>(mapper: F)
+where
+    E: StdError + Send + Sync + 'static,
+    F: Fn(&E) -> Option<AppError> + Send + Sync + 'static,
+{
+    ERROR_MAPPERS.write().unwrap().push(Box::new(move |err| {
+        err.downcast_ref::<E>().and_then(|err| mapper(err))
+    }));
+}
+
+// contextで包まれていても見つかるようにchainをたどる
+fn classify(error: &anyhow::Error) -> Option<AppError> {
+    let mappers = ERROR_MAPPERS.read().unwrap();
+    if mappers.is_empty() {
+        return None;
+    }
+    error
+        .chain()
+        .find_map(|err| mappers.iter().find_map(|mapper| mapper(err)))
+}
+
+/// `Result` のエラーにkindを付けて `AppError` にする
+pub trait AppErrorContext<T>: Sized {
+    fn with_kind(self, kind: ErrorKind) -> Result<T, AppError>;
+
+    fn with_kind_context<C>(self, kind: ErrorKind, context: C) -> Result<T, AppError>
+    where
+        C: Display + Send + Sync + 'static;
+
+    fn or_not_found(self) -> Result<T, AppError> {
+        self.with_kind(ErrorKind::NotFound)
+    }
+}
+
+impl<T, E> AppErrorContext<T> for Result<T, E>
+where
+    E: Into<anyhow::Error>,
+{
+    fn with_kind(self, kind: ErrorKind) -> Result<T, AppError> {
+        self.map_err(|err| AppError::new(kind, err))
+    }
+
+    fn with_kind_context<C>(self, kind: ErrorKind, context: C) -> Result<T, AppError>
+    where
+        C: Display + Send + Sync + 'static,
+    {
+        self.map_err(|err| AppError::new(kind, Into::<anyhow::Error>::into(err).context(context)))
     }
 }
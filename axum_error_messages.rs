@@ -0,0 +1,153 @@
+use std::collections::HashMap;
+use std::sync::{LazyLock, RwLock};
+
+pub const DEFAULT_LANGUAGE: &str = "en";
+
+// 5xxで詳細を隠すときのdetail
+pub const INTERNAL_ERROR_DETAIL_CODE: &str = "internal_error.detail";
+
+type Catalog = HashMap<&'static str, HashMap<&'static str, &'static str>>;
+
+static MESSAGES: LazyLock<RwLock<Catalog>> = LazyLock::new(|| {
+    let mut catalog = Catalog::new();
+    catalog.insert(
+        "en",
+        HashMap::from([
+            ("internal_error", "Internal Server Error"),
+            ("not_found", "Not Found"),
+            ("unauthorized", "Unauthorized"),
+            ("forbidden", "Forbidden"),
+            ("conflict", "Conflict"),
+            ("validation_failed", "Unprocessable Entity"),
+            ("rate_limited", "Too Many Requests"),
+            ("upstream_failure", "Bad Gateway"),
+            (
+                INTERNAL_ERROR_DETAIL_CODE,
+                "An unexpected error occurred. Please contact support with the errorId.",
+            ),
+        ]),
+    );
+    catalog.insert(
+        "ja",
+        HashMap::from([
+            ("internal_error", "サーバーエラーが発生しました"),
+            ("not_found", "見つかりません"),
+            ("unauthorized", "認証が必要です"),
+            ("forbidden", "権限がありません"),
+            ("conflict", "競合が発生しました"),
+            ("validation_failed", "入力内容に誤りがあります"),
+            ("rate_limited", "リクエストが多すぎます"),
+            ("upstream_failure", "外部サービスでエラーが発生しました"),
+            (
+                INTERNAL_ERROR_DETAIL_CODE,
+                "予期しないエラーが発生しました。errorIdを添えてお問い合わせください。",
+            ),
+        ]),
+    );
+    RwLock::new(catalog)
+});
+
+/// 起動時にアプリケーションのメッセージを登録する。`{name}` はparamで置き換えられる
+///
+/// ```ignore
+/// register_messages("en", &[("user.not_found", "User {id} was not found")]);
+/// register_messages("ja", &[("user.not_found", "ユーザー{id}が見つかりません")]);
+/// ```
+pub fn register_messages(language: &'static str, messages: &[(&'static str, &'static str)]) {
+    MESSAGES
+        .write()
+        .unwrap()
+        .entry(language)
+        .or_default()
+        .extend(messages.iter().copied());
+}
+
+/// `language` になければ英語で探す
+pub fn message(language: &str, code: &str, params: &[(String, String)]) -> Option<String> {
+    let catalog = MESSAGES.read().unwrap();
+    let template = catalog
+        .get(language)
+        .and_then(|messages| messages.get(code))
+        .or_else(|| {
+            catalog
+                .get(DEFAULT_LANGUAGE)
+                .and_then(|messages| messages.get(code))
+        })?;
+    Some(
+        params
+            .iter()
+            .fold(template.to_string(), |message, (name, value)| {
+                message.replace(&format!("{{{}}}", name), value)
+            }),
+    )
+}
+
+/// `Accept-Language` から登録されている言語を選ぶ
+pub fn negotiate_language(accept_language: Option<&str>) -> &'static str {
+    let Some(accept_language) = accept_language else {
+        return DEFAULT_LANGUAGE;
+    };
+    let mut ranges = accept_language
+        .split(',')
+        .filter_map(|range| {
+            let mut params = range.split(';');
+            let tag = params.next()?.trim();
+            let q = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            (!tag.is_empty() && q > 0.0).then_some((tag, q))
+        })
+        .collect::<Vec<_>>();
+    ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    let catalog = MESSAGES.read().unwrap();
+    ranges
+        .into_iter()
+        .find_map(|(tag, _)| {
+            // ja-JP は ja として扱う
+            let primary = tag.split('-').next().unwrap_or(tag);
+            catalog
+                .keys()
+                .find(|language| language.eq_ignore_ascii_case(primary))
+                .copied()
+        })
+        .unwrap_or(DEFAULT_LANGUAGE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiate_language_by_q_value() {
+        assert_eq!(negotiate_language(Some("en;q=0.5, ja;q=0.8")), "ja");
+        assert_eq!(negotiate_language(Some("ja;q=0.4, en")), "en");
+        assert_eq!(negotiate_language(Some("fr, ja;q=0.9, en;q=0.8")), "ja");
+    }
+
+    #[test]
+    fn negotiate_language_primary_subtag() {
+        assert_eq!(negotiate_language(Some("ja-JP")), "ja");
+        assert_eq!(negotiate_language(Some("JA-jp,en;q=0.5")), "ja");
+    }
+
+    #[test]
+    fn negotiate_language_wildcard() {
+        assert_eq!(negotiate_language(Some("*")), DEFAULT_LANGUAGE);
+        assert_eq!(negotiate_language(Some("fr, *;q=0.5")), DEFAULT_LANGUAGE);
+    }
+
+    #[test]
+    fn negotiate_language_ignores_q_zero() {
+        assert_eq!(negotiate_language(Some("ja;q=0")), DEFAULT_LANGUAGE);
+        assert_eq!(negotiate_language(Some("ja;q=0, en;q=0.1")), "en");
+    }
+
+    #[test]
+    fn negotiate_language_falls_back_to_en() {
+        assert_eq!(negotiate_language(None), "en");
+        assert_eq!(negotiate_language(Some("")), "en");
+        assert_eq!(negotiate_language(Some("fr-FR, de;q=0.7")), "en");
+    }
+}
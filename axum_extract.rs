@@ -0,0 +1,310 @@
+use std::error::Error as StdError;
+
+use axum::extract::rejection::{FormRejection, JsonRejection, QueryRejection};
+use axum::extract::{FromRequest, FromRequestParts, Request};
+use axum::http::StatusCode;
+use axum::http::request::Parts;
+use axum::response::{IntoResponse, Response};
+
+use super::axum_anyhow_error::{AppError, ErrorKind, FieldError};
+
+/// `axum::Json` のrejectionを `AppError` のフィールドエラーで返す
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AppJson<T>(pub T);
+
+impl<S, T> FromRequest<S> for AppJson<T>
+where
+    axum::Json<T>: FromRequest<S, Rejection = JsonRejection>,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    fn from_request(
+        req: Request,
+        state: &S,
+    ) -> impl Future<Output = Result<Self, Self::Rejection>> + Send {
+        async move {
+            axum::Json::<T>::from_request(req, state)
+                .await
+                .map(|axum::Json(value)| AppJson(value))
+                .map_err(json_rejection)
+        }
+    }
+}
+
+impl<T> IntoResponse for AppJson<T>
+where
+    axum::Json<T>: IntoResponse,
+{
+    fn into_response(self) -> Response {
+        axum::Json(self.0).into_response()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AppForm<T>(pub T);
+
+impl<S, T> FromRequest<S> for AppForm<T>
+where
+    axum::Form<T>: FromRequest<S, Rejection = FormRejection>,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    fn from_request(
+        req: Request,
+        state: &S,
+    ) -> impl Future<Output = Result<Self, Self::Rejection>> + Send {
+        async move {
+            axum::Form::<T>::from_request(req, state)
+                .await
+                .map(|axum::Form(value)| AppForm(value))
+                .map_err(form_rejection)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AppQuery<T>(pub T);
+
+impl<S, T> FromRequestParts<S> for AppQuery<T>
+where
+    axum::extract::Query<T>: FromRequestParts<S, Rejection = QueryRejection>,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    fn from_request_parts(
+        parts: &mut Parts,
+        state: &S,
+    ) -> impl Future<Output = Result<Self, Self::Rejection>> + Send {
+        async move {
+            axum::extract::Query::<T>::from_request_parts(parts, state)
+                .await
+                .map(|axum::extract::Query(value)| AppQuery(value))
+                .map_err(query_rejection)
+        }
+    }
+}
+
+pub fn json_rejection(rejection: JsonRejection) -> AppError {
+    let status = rejection.status();
+    match &rejection {
+        JsonRejection::JsonDataError(err) => invalid(status, deserialize_field_error(err)),
+        JsonRejection::JsonSyntaxError(_) => invalid(
+            status,
+            FieldError::new(None, "invalid_json", rejection.body_text()),
+        ),
+        _ => AppError::msg(ErrorKind::Validation, rejection.body_text()).with_status(status),
+    }
+}
+
+pub fn form_rejection(rejection: FormRejection) -> AppError {
+    let status = rejection.status();
+    match &rejection {
+        FormRejection::FailedToDeserializeForm(err) => {
+            invalid(status, deserialize_field_error(err))
+        }
+        FormRejection::FailedToDeserializeFormBody(err) => {
+            invalid(status, deserialize_field_error(err))
+        }
+        _ => AppError::msg(ErrorKind::Validation, rejection.body_text()).with_status(status),
+    }
+}
+
+pub fn query_rejection(rejection: QueryRejection) -> AppError {
+    let status = rejection.status();
+    match &rejection {
+        QueryRejection::FailedToDeserializeQueryString(err) => {
+            invalid(status, deserialize_field_error(err))
+        }
+        _ => AppError::msg(ErrorKind::Validation, rejection.body_text()).with_status(status),
+    }
+}
+
+fn invalid(status: StatusCode, field_error: FieldError) -> AppError {
+    AppError::invalid_fields(vec![field_error]).with_status(status)
+}
+
+// axumのrejectionはserde_path_to_errorのエラーを包んでいるので一番内側を見る
+fn deserialize_field_error(err: &(dyn StdError + 'static)) -> FieldError {
+    let mut source = err;
+    while let Some(next) = source.source() {
+        source = next;
+    }
+    let message = source.to_string();
+
+    // "user.email: invalid type: ..." のようにpathが先頭に付く
+    let (path, message) = match message.split_once(": ") {
+        Some((path, rest)) if path != "." && !path.contains(' ') => (Some(path), rest),
+        _ => (None, message.as_str()),
+    };
+    // serdeのメッセージはフィールド名を `name` で囲む
+    let quoted = message.split('`').nth(1);
+
+    let code = if message.starts_with("missing field") {
+        "required"
+    } else if message.starts_with("unknown field") {
+        "unknown_field"
+    } else if message.starts_with("invalid type") {
+        "invalid_type"
+    } else if message.starts_with("invalid value")
+        || message.starts_with("invalid length")
+        || message.starts_with("unknown variant")
+    {
+        "invalid_value"
+    } else {
+        "invalid_format"
+    };
+    let field = match (path, code) {
+        (Some(path), "required" | "unknown_field") => {
+            quoted.map(|name| format!("{}.{}", path, name))
+        }
+        (Some(path), _) => Some(path.to_string()),
+        (None, "required" | "unknown_field") => quoted.map(|name| name.to_string()),
+        (None, _) => None,
+    };
+
+    FieldError {
+        field,
+        code: code.to_string(),
+        message: message.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use axum::http::header;
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Debug, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    #[allow(dead_code)]
+    struct Signup {
+        name: String,
+        email: String,
+        age: Option<u32>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    #[allow(dead_code)]
+    struct Nested {
+        user: Signup,
+    }
+
+    fn field_error(err: AppError) -> (Option<String>, String) {
+        let field_errors = err.field_errors();
+        assert_eq!(field_errors.len(), 1, "{:?}", field_errors);
+        (field_errors[0].field.clone(), field_errors[0].code.clone())
+    }
+
+    async fn json<T>(body: &str) -> (Option<String>, String)
+    where
+        T: serde::de::DeserializeOwned + Send,
+    {
+        let req = Request::builder()
+            .method("POST")
+            .uri("/")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        field_error(AppJson::<T>::from_request(req, &()).await.unwrap_err())
+    }
+
+    async fn form(body: &str) -> (Option<String>, String) {
+        let req = Request::builder()
+            .method("POST")
+            .uri("/")
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        field_error(AppForm::<Signup>::from_request(req, &()).await.unwrap_err())
+    }
+
+    async fn query(query: &str) -> (Option<String>, String) {
+        let req = Request::builder()
+            .uri(format!("/?{}", query))
+            .body(Body::empty())
+            .unwrap();
+        let (mut parts, _) = req.into_parts();
+        field_error(
+            AppQuery::<Signup>::from_request_parts(&mut parts, &())
+                .await
+                .unwrap_err(),
+        )
+    }
+
+    fn expected(field: &str, code: &str) -> (Option<String>, String) {
+        (Some(field.to_string()), code.to_string())
+    }
+
+    #[tokio::test]
+    async fn json_field_errors() {
+        assert_eq!(
+            json::<Signup>(r#"{"name":"a"}"#).await,
+            expected("email", "required")
+        );
+        assert_eq!(
+            json::<Signup>(r#"{"name":"a","email":"b","extra":1}"#).await,
+            expected("extra", "unknown_field")
+        );
+        assert_eq!(
+            json::<Signup>(r#"{"name":1,"email":"b"}"#).await,
+            expected("name", "invalid_type")
+        );
+    }
+
+    #[tokio::test]
+    async fn json_nested_field_errors() {
+        assert_eq!(
+            json::<Nested>(r#"{"user":{"name":"a"}}"#).await,
+            expected("user.email", "required")
+        );
+        assert_eq!(
+            json::<Nested>(r#"{"user":{"name":"a","email":"b","extra":1}}"#).await,
+            expected("user.extra", "unknown_field")
+        );
+        assert_eq!(
+            json::<Nested>(r#"{"user":{"name":"a","email":2}}"#).await,
+            expected("user.email", "invalid_type")
+        );
+    }
+
+    #[tokio::test]
+    async fn json_syntax_error() {
+        assert_eq!(
+            json::<Signup>(r#"{"name":"#).await,
+            (None, "invalid_json".to_string())
+        );
+    }
+
+    // urlencodedはネストした構造を持てないのでトップレベルだけ
+    #[tokio::test]
+    async fn form_field_errors() {
+        assert_eq!(form("name=a").await, expected("email", "required"));
+        assert_eq!(
+            form("name=a&email=b&extra=1").await,
+            expected("extra", "unknown_field")
+        );
+        assert_eq!(
+            form("name=a&email=b&age=x").await,
+            expected("age", "invalid_format")
+        );
+    }
+
+    #[tokio::test]
+    async fn query_field_errors() {
+        assert_eq!(query("name=a").await, expected("email", "required"));
+        assert_eq!(
+            query("name=a&email=b&extra=1").await,
+            expected("extra", "unknown_field")
+        );
+        assert_eq!(
+            query("name=a&email=b&age=x").await,
+            expected("age", "invalid_format")
+        );
+    }
+}
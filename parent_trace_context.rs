@@ -1,38 +1,524 @@
+use std::collections::HashMap;
+use std::sync::LazyLock;
+
+use opentelemetry::trace::TraceContextExt;
+use serde::{Deserialize, Serialize};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
 pub const TRACEPARENT_HEADER: &str = "traceparent";
 pub const TRACESTATE_HEADER: &str = "tracestate";
+pub const BAGGAGE_HEADER: &str = "baggage";
 
-#[derive(Debug, Clone)]
+const MAX_TRACESTATE_MEMBERS: usize = 32;
+const MAX_TRACESTATE_LENGTH: usize = 512;
+const MAX_TRACESTATE_KEY_LENGTH: usize = 256;
+const MAX_TRACESTATE_VALUE_LENGTH: usize = 256;
+// W3Cは最低64 member, 8192 byteの伝搬を求めるだけなので、opentelemetry SDKの上限(180 member)に合わせる
+// https://www.w3.org/TR/baggage/#limits
+const MAX_BAGGAGE_MEMBERS: usize = 180;
+const MAX_BAGGAGE_LENGTH: usize = 8192;
+const MAX_HEADER_LENGTH: usize = 8192;
+// jaegerのbaggageは `uberctx-<key>` で、propagatorのfieldsに出てこない
+const JAEGER_BAGGAGE_PREFIX: &str = "uberctx-";
+
+/// 不正なtrace headerの扱い。request extensionに入れておくと抽出時に参照される
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum InvalidTraceHeaders {
+    /// debugログとmetricを出して無視する
+    #[default]
+    Ignore,
+    /// 400を返す
+    Reject,
+}
+
+/// propagatorが必要とするheaderを持っておく。keyは小文字
+///
+/// serializeするとheaderのmapになるので、jobやmessageに入れて別プロセスに渡せる
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(from = "HashMap<String, String>", into = "HashMap<String, String>")]
 pub struct ParentTraceContext {
-    parent: Option<String>,
-    state: Option<String>,
+    headers: HashMap<String, String>,
 }
 
 impl ParentTraceContext {
     pub fn new(parent: Option<String>, state: Option<String>) -> Self {
-        Self { parent, state }
+        let mut context = Self::default();
+        context.insert_opt(TRACEPARENT_HEADER, parent);
+        context.insert_opt(TRACESTATE_HEADER, state);
+        context
+    }
+
+    /// `propagation_fields` に含まれるheaderだけを残す
+    pub fn from_headers<K, V>(headers: impl IntoIterator<Item = (K, V)>) -> Self
+    where
+        K: AsRef<str>,
+        V: Into<String>,
+    {
+        let fields = propagation_fields();
+        let mut context = Self::default();
+        for (name, value) in headers {
+            let name = name.as_ref().to_ascii_lowercase();
+            if is_propagation_field(&fields, &name) {
+                context.insert(name, value.into());
+            }
+        }
+        context
+    }
+
+    /// 任意のcarrierからpropagatorが使うheaderだけを取り出す
+    pub fn from_extractor(extractor: &impl opentelemetry::propagation::Extractor) -> Self {
+        let fields = propagation_fields();
+        let mut context = Self::default();
+        for name in extractor.keys() {
+            let name = name.to_ascii_lowercase();
+            if is_propagation_field(&fields, &name)
+                && let Some(value) = extractor.get(&name)
+            {
+                let value = value.to_string();
+                context.insert(name, value);
+            }
+        }
+        context
+    }
+
+    pub fn with_baggage(mut self, baggage: Option<String>) -> Self {
+        self.insert_opt(BAGGAGE_HEADER, baggage);
+        self
+    }
+
+    pub fn insert(&mut self, name: impl Into<String>, value: String) {
+        let name = name.into().to_ascii_lowercase();
+        if name == BAGGAGE_HEADER && !is_valid_baggage(&value) {
+            return;
+        }
+        self.headers.insert(name, value);
+    }
+
+    fn insert_opt(&mut self, name: &str, value: Option<String>) {
+        if let Some(value) = value {
+            self.insert(name, value);
+        }
+    }
+
+    pub fn headers(&self) -> &HashMap<String, String> {
+        &self.headers
+    }
+
+    /// messageのheaderに書き込むとき用
+    pub fn into_headers(self) -> HashMap<String, String> {
+        self.headers
+    }
+
+    pub fn from_context(cx: &opentelemetry::Context) -> Self {
+        let mut context = Self::default();
+        opentelemetry::global::get_text_map_propagator(|prop| {
+            prop.inject_context(cx, &mut context)
+        });
+        context
+    }
+
+    /// 現在のspanのcontext。handlerの中でjobを積むときに使う
+    pub fn current() -> Self {
+        Self::from_context(&current_context())
     }
 
+    /// 別のtraceとして始めて、linkでつなぐとき用
+    pub fn link(&self) -> Option<opentelemetry::trace::Link> {
+        let span_context = self.get().span().span_context().clone();
+        span_context
+            .is_valid()
+            .then(|| opentelemetry::trace::Link::with_context(span_context))
+    }
+
+    /// `span` をこのcontextの子にする
+    pub fn continue_in(&self, span: &tracing::Span) {
+        span.set_parent(self.get());
+    }
+
+    /// `span` からこのcontextへlinkを張る
+    pub fn link_from(&self, span: &tracing::Span) {
+        let span_context = self.get().span().span_context().clone();
+        if span_context.is_valid() {
+            span.add_link(span_context);
+        }
+    }
+
+    /// baggageは `opentelemetry::baggage::BaggageExt` で取り出せる
     pub fn get(&self) -> opentelemetry::Context {
         opentelemetry::global::get_text_map_propagator(|prop| prop.extract(self))
     }
 }
 
+// 外から来たmapもinsertを通して小文字化と検証をする
+// Deserializeでも使われるので、外から来たmapと同じようにpropagatorのheaderだけ残す
+impl From<HashMap<String, String>> for ParentTraceContext {
+    fn from(headers: HashMap<String, String>) -> Self {
+        Self::from_headers(headers)
+    }
+}
+
+impl From<ParentTraceContext> for HashMap<String, String> {
+    fn from(context: ParentTraceContext) -> Self {
+        context.headers
+    }
+}
+
 impl opentelemetry::propagation::Extractor for ParentTraceContext {
     fn get(&self, key: &str) -> Option<&str> {
-        if key == TRACEPARENT_HEADER {
-            self.parent.as_deref()
-        } else if key == TRACESTATE_HEADER {
-            self.state.as_deref()
-        } else {
-            None
-        }
+        self.headers
+            .get(&key.to_ascii_lowercase())
+            .map(|value| value.as_str())
     }
 
     fn keys(&self) -> Vec<&str> {
-        self.parent
-            .as_deref()
-            .into_iter()
-            .chain(self.state.as_deref())
-            .collect()
+        self.headers.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+impl opentelemetry::propagation::Injector for ParentTraceContext {
+    fn set(&mut self, key: &str, value: String) {
+        self.insert(key, value);
+    }
+}
+
+/// 現在のOpenTelemetryのcontext
+///
+/// GraphQLのextensionのようにOTelのspanを `FutureExt::with_context` で直接付けている所では
+/// `opentelemetry::Context::current()` を、そうでなければtracingのspanのcontextを使う
+pub fn current_context() -> opentelemetry::Context {
+    let otel_cx = opentelemetry::Context::current();
+    let tracing_cx = tracing::Span::current().context();
+    // tracingのspanから作ったcontextはnon-recordingで、tracingのspanの方が内側のことがある
+    if otel_cx.span().span_context().is_valid()
+        && (otel_cx.span().is_recording() || !tracing_cx.span().span_context().is_valid())
+    {
+        otel_cx
+    } else {
+        tracing_cx
+    }
+}
+
+/// jobのpayloadにtrace contextを添える
+///
+/// ```ignore
+/// queue.push(TracedJob::new(SendMail { to }))?;
+///
+/// // consumer
+/// let job: TracedJob<SendMail> = queue.pop()?;
+/// let span = tracing::info_span!("send_mail");
+/// job.trace_context.continue_in(&span);
+/// handle(job.payload).instrument(span).await;
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TracedJob<T> {
+    pub trace_context: ParentTraceContext,
+    pub payload: T,
+}
+
+impl<T> TracedJob<T> {
+    pub fn new(payload: T) -> Self {
+        Self {
+            trace_context: ParentTraceContext::current(),
+            payload,
+        }
+    }
+
+    pub fn into_parts(self) -> (ParentTraceContext, T) {
+        (self.trace_context, self.payload)
+    }
+}
+
+/// globalのpropagatorが読み書きするheader名。`OTEL_PROPAGATORS` で変わる
+pub fn propagation_fields() -> Vec<String> {
+    let fields = opentelemetry::global::get_text_map_propagator(|prop| {
+        prop.fields()
+            .map(|field| field.to_ascii_lowercase())
+            .collect::<Vec<_>>()
+    });
+    // propagatorを設定していないときもW3Cのheaderは持ち回る
+    if fields.is_empty() {
+        [TRACEPARENT_HEADER, TRACESTATE_HEADER, BAGGAGE_HEADER]
+            .map(|field| field.to_string())
+            .to_vec()
+    } else {
+        fields
+    }
+}
+
+pub fn is_propagation_field(fields: &[String], name: &str) -> bool {
+    fields.iter().any(|field| field == name) || name.starts_with(JAEGER_BAGGAGE_PREFIX)
+}
+
+/// allow-listにあるbaggageをspanのattribute (`baggage.<key>`) にする
+pub fn baggage_attributes(
+    cx: &opentelemetry::Context,
+    allow_list: &[String],
+) -> Vec<opentelemetry::KeyValue> {
+    use opentelemetry::baggage::BaggageExt;
+
+    let baggage = cx.baggage();
+    allow_list
+        .iter()
+        .filter_map(|key| {
+            baggage.get(key.as_str()).map(|value| {
+                opentelemetry::KeyValue::new(format!("baggage.{}", key), value.to_string())
+            })
+        })
+        .collect()
+}
+
+static INVALID_HEADERS: LazyLock<opentelemetry::metrics::Counter<u64>> = LazyLock::new(|| {
+    opentelemetry::global::meter("parent-trace-context")
+        .u64_counter("trace_context.invalid_headers")
+        .build()
+});
+
+/// headerの値が正しければ `&str` で返す。不正ならdebugログとmetricを出して `None`
+pub fn checked_header<'a>(name: &str, value: &'a [u8]) -> Option<&'a str> {
+    let checked = value
+        .is_ascii()
+        .then(|| std::str::from_utf8(value).ok())
+        .flatten()
+        .filter(|value| match name {
+            TRACEPARENT_HEADER => is_valid_traceparent(value),
+            TRACESTATE_HEADER => is_valid_tracestate(value),
+            BAGGAGE_HEADER => is_valid_baggage(value),
+            _ => value.len() <= MAX_HEADER_LENGTH,
+        });
+    if checked.is_none() {
+        tracing::debug!(header = name, "invalid trace context header");
+        INVALID_HEADERS.add(
+            1,
+            &[opentelemetry::KeyValue::new("header", name.to_string())],
+        );
+    }
+    checked
+}
+
+// https://www.w3.org/TR/trace-context/#traceparent-header
+pub fn is_valid_traceparent(value: &str) -> bool {
+    let parts = value.split('-').collect::<Vec<_>>();
+    if parts.len() < 4 {
+        return false;
+    }
+    let (version, trace_id, parent_id, flags) = (parts[0], parts[1], parts[2], parts[3]);
+    if !is_lower_hex(version, 2) || version == "ff" {
+        return false;
+    }
+    // 未知のversionは後ろにfieldが増えていてもよい
+    if version == "00" && parts.len() != 4 {
+        return false;
+    }
+    is_lower_hex(trace_id, 32)
+        && is_lower_hex(parent_id, 16)
+        && is_lower_hex(flags, 2)
+        && trace_id.bytes().any(|b| b != b'0')
+        && parent_id.bytes().any(|b| b != b'0')
+}
+
+// https://www.w3.org/TR/trace-context/#tracestate-header
+pub fn is_valid_tracestate(value: &str) -> bool {
+    if value.len() > MAX_TRACESTATE_LENGTH {
+        return false;
+    }
+    let members = value
+        .split(',')
+        .map(|member| member.trim_matches([' ', '\t']))
+        .filter(|member| !member.is_empty())
+        .collect::<Vec<_>>();
+    members.len() <= MAX_TRACESTATE_MEMBERS
+        && members.iter().all(|member| {
+            let Some((key, value)) = member.split_once('=') else {
+                return false;
+            };
+            is_valid_tracestate_key(key) && is_valid_tracestate_value(value)
+        })
+}
+
+// 中身の解釈はBaggagePropagatorに任せて、大きさだけ制限する
+pub fn is_valid_baggage(value: &str) -> bool {
+    value.len() <= MAX_BAGGAGE_LENGTH && value.split(',').count() <= MAX_BAGGAGE_MEMBERS
+}
+
+fn is_valid_tracestate_key(key: &str) -> bool {
+    let valid_chars = |part: &str| {
+        part.bytes().all(|b| {
+            b.is_ascii_lowercase() || b.is_ascii_digit() || matches!(b, b'_' | b'-' | b'*' | b'/')
+        })
+    };
+    let (tenant, system) = match key.split_once('@') {
+        Some((tenant, system)) => (tenant, Some(system)),
+        None => (key, None),
+    };
+    !tenant.is_empty()
+        && key.len() <= MAX_TRACESTATE_KEY_LENGTH
+        && valid_chars(tenant)
+        && system.is_none_or(|system| {
+            system
+                .bytes()
+                .next()
+                .is_some_and(|b| b.is_ascii_lowercase())
+                && valid_chars(system)
+        })
+        && key
+            .bytes()
+            .next()
+            .is_some_and(|b| b.is_ascii_lowercase() || b.is_ascii_digit())
+}
+
+fn is_valid_tracestate_value(value: &str) -> bool {
+    !value.is_empty()
+        && value.len() <= MAX_TRACESTATE_VALUE_LENGTH
+        && !value.ends_with(' ')
+        && value
+            .bytes()
+            .all(|b| (0x20..=0x7e).contains(&b) && b != b',' && b != b'=')
+}
+
+fn is_lower_hex(value: &str, len: usize) -> bool {
+    value.len() == len
+        && value
+            .bytes()
+            .all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+    const PARENT_ID: &str = "00f067aa0ba902b7";
+
+    #[test]
+    fn traceparent_valid() {
+        assert!(is_valid_traceparent(&format!(
+            "00-{}-{}-01",
+            TRACE_ID, PARENT_ID
+        )));
+        assert!(is_valid_traceparent(&format!(
+            "00-{}-{}-00",
+            TRACE_ID, PARENT_ID
+        )));
+    }
+
+    #[test]
+    fn traceparent_all_zero_ids() {
+        assert!(!is_valid_traceparent(&format!(
+            "00-{}-{}-01",
+            "0".repeat(32),
+            PARENT_ID
+        )));
+        assert!(!is_valid_traceparent(&format!(
+            "00-{}-{}-01",
+            TRACE_ID,
+            "0".repeat(16)
+        )));
+    }
+
+    #[test]
+    fn traceparent_version() {
+        assert!(!is_valid_traceparent(&format!(
+            "ff-{}-{}-01",
+            TRACE_ID, PARENT_ID
+        )));
+        // 未知のversionは後ろのfieldを許す
+        assert!(is_valid_traceparent(&format!(
+            "01-{}-{}-01-extra",
+            TRACE_ID, PARENT_ID
+        )));
+        assert!(!is_valid_traceparent(&format!(
+            "00-{}-{}-01-extra",
+            TRACE_ID, PARENT_ID
+        )));
+        assert!(!is_valid_traceparent(&format!(
+            "0-{}-{}-01",
+            TRACE_ID, PARENT_ID
+        )));
+    }
+
+    #[test]
+    fn traceparent_uppercase_hex() {
+        assert!(!is_valid_traceparent(&format!(
+            "00-{}-{}-01",
+            TRACE_ID.to_uppercase(),
+            PARENT_ID
+        )));
+        assert!(!is_valid_traceparent(&format!(
+            "00-{}-{}-01",
+            TRACE_ID,
+            PARENT_ID.to_uppercase()
+        )));
+        assert!(!is_valid_traceparent(&format!(
+            "0A-{}-{}-01",
+            TRACE_ID, PARENT_ID
+        )));
+    }
+
+    #[test]
+    fn traceparent_malformed() {
+        assert!(!is_valid_traceparent(""));
+        assert!(!is_valid_traceparent(&format!(
+            "00-{}-{}",
+            TRACE_ID, PARENT_ID
+        )));
+        assert!(!is_valid_traceparent(&format!(
+            "00-{}-{}-01",
+            &TRACE_ID[..31],
+            PARENT_ID
+        )));
+        assert!(!is_valid_traceparent(&format!(
+            "00-{}-{}-1",
+            TRACE_ID, PARENT_ID
+        )));
+    }
+
+    #[test]
+    fn tracestate_valid() {
+        assert!(is_valid_tracestate(
+            "rojo=00f067aa0ba902b7,congo=t61rcWkgMzE"
+        ));
+        assert!(is_valid_tracestate("tenant@system=value"));
+        assert!(is_valid_tracestate("a=1, ,b=2"));
+        assert!(is_valid_tracestate(""));
+    }
+
+    #[test]
+    fn tracestate_invalid_members() {
+        assert!(!is_valid_tracestate("Rojo=1"));
+        assert!(!is_valid_tracestate("rojo"));
+        assert!(!is_valid_tracestate("rojo=a=b"));
+        assert!(!is_valid_tracestate("rojo="));
+        assert!(!is_valid_tracestate("@system=1"));
+        assert!(!is_valid_tracestate("tenant@1system=1"));
+    }
+
+    #[test]
+    fn tracestate_member_limit() {
+        let members = |n: usize| {
+            (0..n)
+                .map(|i| format!("k{}=v", i))
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        assert!(is_valid_tracestate(&members(MAX_TRACESTATE_MEMBERS)));
+        assert!(!is_valid_tracestate(&members(MAX_TRACESTATE_MEMBERS + 1)));
+    }
+
+    #[test]
+    fn tracestate_length_limits() {
+        let key = "k".repeat(MAX_TRACESTATE_KEY_LENGTH);
+        assert!(is_valid_tracestate(&format!("{}=v", key)));
+        assert!(!is_valid_tracestate(&format!("{}k=v", key)));
+
+        let value = "v".repeat(MAX_TRACESTATE_VALUE_LENGTH);
+        assert!(is_valid_tracestate(&format!("k={}", value)));
+        assert!(!is_valid_tracestate(&format!("k={}v", value)));
+
+        // 各memberは正しくても全体が長すぎる
+        let value = "v".repeat(200);
+        assert!(!is_valid_tracestate(&format!(
+            "a={},b={},c={}",
+            value, value, value
+        )));
     }
 }
@@ -1,41 +1,41 @@
-use std::collections::HashMap;
+use axum::{
+    extract::FromRequestParts,
+    http::{StatusCode, request::Parts},
+    response::{IntoResponse, Response},
+};
 
-use axum::{extract::FromRequestParts, http::request::Parts, response::Response};
+pub use super::parent_trace_context::{
+    BAGGAGE_HEADER, InvalidTraceHeaders, ParentTraceContext, TRACEPARENT_HEADER, TRACESTATE_HEADER,
+};
+use super::parent_trace_context_carrier::{HeaderExtractor, has_invalid_headers};
 
-pub use super::parent_trace_context::{ParentTraceContext, TRACEPARENT_HEADER, TRACESTATE_HEADER};
-
-#[derive(Debug, Clone)]
-pub struct ParentTraceContextAxum {
-    headers: HashMap<&'static str, Option<String>>,
-}
+#[derive(Debug, Clone, Default)]
+pub struct ParentTraceContextAxum(pub ParentTraceContext);
 
 impl ParentTraceContextAxum {
     async fn from_request_parts_impl<S: Send + Sync>(
         parts: &mut Parts,
         _state: &S,
     ) -> Result<Self, Response> {
-        Ok(ParentTraceContextAxum {
-            headers: HashMap::from_iter(
-                [
-                    (
-                        TRACEPARENT_HEADER,
-                        parts
-                            .headers
-                            .get(TRACEPARENT_HEADER)
-                            .map(|x| x.to_str().unwrap().to_string()),
-                    ),
-                    (
-                        TRACESTATE_HEADER,
-                        parts
-                            .headers
-                            .get(TRACESTATE_HEADER)
-                            .map(|x| x.to_str().unwrap().to_string()),
-                    ),
-                ]
-                .into_iter()
-                .filter(|x| x.1.is_some()),
-            ),
-        })
+        // ParentTraceContextLayerで検証済み
+        if let Some(parent) = parts.extensions.get::<ParentTraceContext>() {
+            return Ok(ParentTraceContextAxum(parent.clone()));
+        }
+        let invalid_headers = parts
+            .extensions
+            .get::<InvalidTraceHeaders>()
+            .copied()
+            .unwrap_or_default();
+        if invalid_headers == InvalidTraceHeaders::Reject && has_invalid_headers(&parts.headers) {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "invalid trace context header".to_string(),
+            )
+                .into_response());
+        }
+        Ok(ParentTraceContextAxum(ParentTraceContext::from_extractor(
+            &HeaderExtractor(&parts.headers),
+        )))
     }
 }
 
@@ -55,17 +55,6 @@ where
 
 impl From<ParentTraceContextAxum> for ParentTraceContext {
     fn from(value: ParentTraceContextAxum) -> Self {
-        ParentTraceContext::new(
-            value
-                .headers
-                .get(TRACEPARENT_HEADER)
-                .cloned()
-                .unwrap_or_default(),
-            value
-                .headers
-                .get(TRACESTATE_HEADER)
-                .cloned()
-                .unwrap_or_default(),
-        )
+        value.0
     }
 }
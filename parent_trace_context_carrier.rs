@@ -0,0 +1,43 @@
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+
+use super::parent_trace_context::{checked_header, is_propagation_field, propagation_fields};
+
+// `HashMap<String, String>` にはopentelemetryがExtractor/Injectorを実装している
+
+/// `HeaderMap` をコピーせずにpropagatorへ渡す。不正な値は `None` になる
+#[derive(Debug, Clone, Copy)]
+pub struct HeaderExtractor<'a>(pub &'a HeaderMap);
+
+impl opentelemetry::propagation::Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0
+            .get(key)
+            .and_then(|value| checked_header(key, value.as_bytes()))
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+#[derive(Debug)]
+pub struct HeaderInjector<'a>(pub &'a mut HeaderMap);
+
+impl opentelemetry::propagation::Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let Ok(name) = HeaderName::from_bytes(key.as_bytes())
+            && let Ok(value) = HeaderValue::from_str(&value)
+        {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+/// propagatorが使うheaderに不正な値があるか
+pub fn has_invalid_headers(headers: &HeaderMap) -> bool {
+    let fields = propagation_fields();
+    headers.iter().any(|(name, value)| {
+        is_propagation_field(&fields, name.as_str())
+            && checked_header(name.as_str(), value.as_bytes()).is_none()
+    })
+}
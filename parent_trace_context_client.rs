@@ -0,0 +1,132 @@
+use std::task::{Context, Poll};
+
+use axum::http::{HeaderMap, Request, Response};
+use futures_util::future::BoxFuture;
+use opentelemetry::trace::FutureExt;
+use tower::{Layer, Service};
+use tracing::Instrument;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use super::parent_trace_context::current_context;
+use super::parent_trace_context_carrier::HeaderInjector;
+
+/// `opentelemetry::Context` のtrace headerを書き込む
+///
+/// reqwestでは `RequestBuilder::headers(trace_headers())` のように使う
+pub fn inject_headers(cx: &opentelemetry::Context, headers: &mut HeaderMap) {
+    opentelemetry::global::get_text_map_propagator(|prop| {
+        prop.inject_context(cx, &mut HeaderInjector(headers))
+    });
+}
+
+/// 現在のspanのtrace header
+pub fn trace_headers() -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    inject_headers(&current_context(), &mut headers);
+    headers
+}
+
+/// 外部サービスを呼ぶときにclient spanを作り、trace headerを付けるlayer
+///
+/// ```ignore
+/// let client = tower::ServiceBuilder::new()
+///     .layer(InjectTraceContextLayer::new())
+///     .service(hyper_util::client::legacy::Client::builder(TokioExecutor::new()).build_http());
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct InjectTraceContextLayer {}
+
+impl InjectTraceContextLayer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<S> Layer<S> for InjectTraceContextLayer {
+    type Service = InjectTraceContextService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        InjectTraceContextService { inner }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct InjectTraceContextService<S> {
+    inner: S,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for InjectTraceContextService<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    ReqBody: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<ReqBody>) -> Self::Future {
+        let method = req.method().as_str();
+        let uri = req.uri();
+        let server_port = uri.port_u16().or(match uri.scheme_str() {
+            Some("https") => Some(443),
+            Some("http") => Some(80),
+            _ => None,
+        });
+        // queryやuserinfoには秘密が入りうるので含めない
+        let url = match (uri.scheme_str(), uri.host()) {
+            (Some(scheme), Some(host)) => match uri.port_u16() {
+                Some(port) => format!("{}://{}:{}{}", scheme, host, port, uri.path()),
+                None => format!("{}://{}{}", scheme, host, uri.path()),
+            },
+            _ => uri.path().to_string(),
+        };
+
+        // https://opentelemetry.io/docs/specs/semconv/http/http-spans/#http-client
+        let span = tracing::info_span!(
+            "HTTP request",
+            otel.name = method,
+            otel.kind = "client",
+            otel.status_code = tracing::field::Empty,
+            http.request.method = method,
+            http.response.status_code = tracing::field::Empty,
+            url.full = %url,
+            server.address = uri.host(),
+            server.port = server_port,
+        );
+        // resolverの中ではOTelのspanがtracingのspanより内側にある
+        span.set_parent(current_context());
+        let otel_cx = span.context();
+        inject_headers(&otel_cx, req.headers_mut());
+
+        let mut inner = self.inner.clone();
+        std::mem::swap(&mut self.inner, &mut inner);
+
+        let record_span = span.clone();
+        Box::pin(
+            async move {
+                let result = inner.call(req).await;
+                match &result {
+                    Ok(response) => {
+                        let status = response.status();
+                        record_span.record("http.response.status_code", status.as_u16());
+                        // clientでは4xxもエラー
+                        if status.is_client_error() || status.is_server_error() {
+                            record_span.record("otel.status_code", "ERROR");
+                        }
+                    }
+                    Err(_) => {
+                        record_span.record("otel.status_code", "ERROR");
+                    }
+                }
+                result
+            }
+            .with_context(otel_cx)
+            .instrument(span),
+        )
+    }
+}
@@ -0,0 +1,151 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use axum::extract::{ConnectInfo, MatchedPath};
+use axum::http::{Request, Response, StatusCode, header};
+use futures_util::future::BoxFuture;
+use opentelemetry::trace::FutureExt;
+use tower::{Layer, Service};
+use tracing::Instrument;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use super::parent_trace_context::{InvalidTraceHeaders, ParentTraceContext, baggage_attributes};
+use super::parent_trace_context_carrier::{HeaderExtractor, has_invalid_headers};
+
+/// `traceparent` などのヘッダーからparentを取り出し、HTTPのserver spanを作るlayer
+///
+/// `MatchedPath` を使うので `Router::layer` で追加する
+#[derive(Debug, Clone, Default)]
+pub struct ParentTraceContextLayer {
+    invalid_headers: InvalidTraceHeaders,
+    baggage_attributes: Arc<[String]>,
+}
+
+impl ParentTraceContextLayer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn invalid_headers(mut self, invalid_headers: InvalidTraceHeaders) -> Self {
+        self.invalid_headers = invalid_headers;
+        self
+    }
+
+    /// 指定したkeyのbaggageをspanのattributeにコピーする
+    pub fn baggage_attributes(mut self, allow_list: impl IntoIterator<Item = String>) -> Self {
+        self.baggage_attributes = allow_list.into_iter().collect();
+        self
+    }
+}
+
+impl<S> Layer<S> for ParentTraceContextLayer {
+    type Service = ParentTraceContextService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ParentTraceContextService {
+            inner,
+            invalid_headers: self.invalid_headers,
+            baggage_attributes: self.baggage_attributes.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ParentTraceContextService<S> {
+    inner: S,
+    invalid_headers: InvalidTraceHeaders,
+    baggage_attributes: Arc<[String]>,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for ParentTraceContextService<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    ReqBody: Send + 'static,
+    ResBody: Default,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<ReqBody>) -> Self::Future {
+        // ParentTraceContextAxumも同じ設定で抽出する
+        req.extensions_mut().insert(self.invalid_headers);
+        if self.invalid_headers == InvalidTraceHeaders::Reject && has_invalid_headers(req.headers())
+        {
+            let mut response = Response::new(ResBody::default());
+            *response.status_mut() = StatusCode::BAD_REQUEST;
+            return Box::pin(async move { Ok(response) });
+        }
+
+        // 検証はここで一度だけ行い、ParentTraceContextAxumはextensionの値を使う
+        let parent = ParentTraceContext::from_extractor(&HeaderExtractor(req.headers()));
+        let parent_cx = parent.get();
+        req.extensions_mut().insert(parent);
+
+        let method = req.method().as_str();
+        let route = req
+            .extensions()
+            .get::<MatchedPath>()
+            .map(|path| path.as_str());
+        let peer = req
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| *addr);
+        let user_agent = req
+            .headers()
+            .get(header::USER_AGENT)
+            .and_then(|value| value.to_str().ok());
+        let peer_address = peer.map(|addr| addr.ip().to_string());
+        let span_name = match route {
+            Some(route) => format!("{} {}", method, route),
+            None => method.to_string(),
+        };
+
+        // https://opentelemetry.io/docs/specs/semconv/http/http-spans/#http-server
+        let span = tracing::info_span!(
+            "HTTP request",
+            otel.name = %span_name,
+            otel.kind = "server",
+            otel.status_code = tracing::field::Empty,
+            http.request.method = method,
+            http.route = route,
+            http.response.status_code = tracing::field::Empty,
+            url.path = req.uri().path(),
+            url.scheme = req.uri().scheme_str(),
+            user_agent.original = user_agent,
+            client.address = peer_address.as_deref(),
+            network.peer.address = peer_address.as_deref(),
+            network.peer.port = peer.map(|addr| addr.port()),
+            request_id = tracing::field::Empty,
+        );
+        for attribute in baggage_attributes(&parent_cx, &self.baggage_attributes) {
+            span.set_attribute(attribute.key, attribute.value);
+        }
+        span.set_parent(parent_cx);
+        let otel_cx = span.context();
+
+        let mut inner = self.inner.clone();
+        std::mem::swap(&mut self.inner, &mut inner);
+
+        let record_span = span.clone();
+        Box::pin(
+            async move {
+                let response = inner.call(req).await?;
+                let status = response.status();
+                record_span.record("http.response.status_code", status.as_u16());
+                if status.is_server_error() {
+                    record_span.record("otel.status_code", "ERROR");
+                }
+                Ok(response)
+            }
+            .with_context(otel_cx)
+            .instrument(span),
+        )
+    }
+}
@@ -0,0 +1,112 @@
+use tonic::metadata::{KeyRef, MetadataKey, MetadataMap, MetadataValue};
+use tonic::service::Interceptor;
+
+use super::parent_trace_context::{
+    InvalidTraceHeaders, ParentTraceContext, checked_header, current_context, is_propagation_field,
+    propagation_fields,
+};
+
+/// `MetadataMap` をコピーせずにpropagatorへ渡す。binaryのmetadataは見ない
+#[derive(Debug, Clone, Copy)]
+pub struct MetadataExtractor<'a>(pub &'a MetadataMap);
+
+impl opentelemetry::propagation::Extractor for MetadataExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0
+            .get(key)
+            .and_then(|value| checked_header(key, value.as_bytes()))
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0
+            .keys()
+            .filter_map(|key| match key {
+                KeyRef::Ascii(key) => Some(key.as_str()),
+                KeyRef::Binary(_) => None,
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+pub struct MetadataInjector<'a>(pub &'a mut MetadataMap);
+
+impl opentelemetry::propagation::Injector for MetadataInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let Ok(key) = MetadataKey::from_bytes(key.as_bytes())
+            && let Ok(value) = MetadataValue::try_from(&value)
+        {
+            self.0.insert(key, value);
+        }
+    }
+}
+
+/// server側。`ParentTraceContext` をrequestのextensionに入れる
+///
+/// ```ignore
+/// let svc = GreeterServer::with_interceptor(greeter, ParentTraceContextInterceptor::new());
+///
+/// // handler
+/// let parent = request.extensions().get::<ParentTraceContext>().cloned().unwrap_or_default();
+/// ```
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ParentTraceContextInterceptor {
+    invalid_headers: InvalidTraceHeaders,
+}
+
+impl ParentTraceContextInterceptor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn invalid_headers(mut self, invalid_headers: InvalidTraceHeaders) -> Self {
+        self.invalid_headers = invalid_headers;
+        self
+    }
+}
+
+impl Interceptor for ParentTraceContextInterceptor {
+    fn call(
+        &mut self,
+        mut request: tonic::Request<()>,
+    ) -> Result<tonic::Request<()>, tonic::Status> {
+        if self.invalid_headers == InvalidTraceHeaders::Reject
+            && has_invalid_metadata(request.metadata())
+        {
+            return Err(tonic::Status::invalid_argument(
+                "invalid trace context metadata",
+            ));
+        }
+        let parent = ParentTraceContext::from_extractor(&MetadataExtractor(request.metadata()));
+        request.extensions_mut().insert(parent);
+        Ok(request)
+    }
+}
+
+/// client側。現在のspanのtrace contextをmetadataに書き込む
+#[derive(Debug, Clone, Copy, Default)]
+pub struct InjectTraceContextInterceptor;
+
+impl Interceptor for InjectTraceContextInterceptor {
+    fn call(
+        &mut self,
+        mut request: tonic::Request<()>,
+    ) -> Result<tonic::Request<()>, tonic::Status> {
+        let cx = current_context();
+        opentelemetry::global::get_text_map_propagator(|prop| {
+            prop.inject_context(&cx, &mut MetadataInjector(request.metadata_mut()))
+        });
+        Ok(request)
+    }
+}
+
+fn has_invalid_metadata(metadata: &MetadataMap) -> bool {
+    let fields = propagation_fields();
+    metadata.iter().any(|entry| match entry {
+        tonic::metadata::KeyAndValueRef::Ascii(key, value) => {
+            is_propagation_field(&fields, key.as_str())
+                && checked_header(key.as_str(), value.as_bytes()).is_none()
+        }
+        tonic::metadata::KeyAndValueRef::Binary(_, _) => false,
+    })
+}
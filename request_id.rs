@@ -0,0 +1,142 @@
+use std::fmt::Display;
+use std::task::{Context, Poll};
+
+use axum::http::{HeaderName, HeaderValue, Request, Response};
+use futures_util::future::BoxFuture;
+use tower::{Layer, Service};
+use tracing::Instrument;
+
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+tokio::task_local! {
+    static REQUEST_ID: RequestId;
+}
+
+/// リクエストごとのID。requestのextensionとtask localに入る
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RequestId(String);
+
+impl RequestId {
+    pub fn new() -> Self {
+        Self(sentry::types::Uuid::new_v4().to_string())
+    }
+
+    /// 受け取ったIDがログに出して安全なものでなければ `None`
+    pub fn parse(value: &[u8]) -> Option<Self> {
+        (!value.is_empty()
+            && value.len() <= MAX_REQUEST_ID_LENGTH
+            && value
+                .iter()
+                .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b':')))
+        .then(|| Self(String::from_utf8_lossy(value).into_owned()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// `RequestIdLayer` の中で動いているタスクならそのID
+    ///
+    /// `tokio::spawn` したタスクには引き継がれないので `scope` で渡す
+    pub fn current() -> Option<Self> {
+        REQUEST_ID.try_with(|id| id.clone()).ok()
+    }
+
+    pub async fn scope<F: Future>(self, f: F) -> F::Output {
+        REQUEST_ID.scope(self, f).await
+    }
+}
+
+impl Default for RequestId {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Display for RequestId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// `X-Request-Id` を読むか生成し、spanの `request_id` に記録してレスポンスに返すlayer
+///
+/// `ParentTraceContextLayer` のspanに記録するので、それより内側に置く
+///
+/// ```ignore
+/// Router::new()
+///     .layer(RequestIdLayer::new())
+///     .layer(ParentTraceContextLayer::new())
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct RequestIdLayer {}
+
+impl RequestIdLayer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<S> Layer<S> for RequestIdLayer {
+    type Service = RequestIdService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RequestIdService { inner }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RequestIdService<S> {
+    inner: S,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for RequestIdService<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    ReqBody: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<ReqBody>) -> Self::Future {
+        let request_id = req
+            .headers()
+            .get(&REQUEST_ID_HEADER)
+            .and_then(|value| RequestId::parse(value.as_bytes()))
+            .unwrap_or_default();
+        req.extensions_mut().insert(request_id.clone());
+
+        // 外側にspanがなければログに出すためのspanを作る
+        let current = tracing::Span::current();
+        let span = if current.is_none() {
+            tracing::info_span!("request", request_id = %request_id)
+        } else {
+            current.record("request_id", request_id.as_str());
+            current
+        };
+
+        let mut inner = self.inner.clone();
+        std::mem::swap(&mut self.inner, &mut inner);
+
+        Box::pin(
+            request_id
+                .clone()
+                .scope(async move {
+                    let mut response = inner.call(req).await?;
+                    if let Ok(value) = HeaderValue::from_str(request_id.as_str()) {
+                        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+                    }
+                    Ok(response)
+                })
+                .instrument(span),
+        )
+    }
+}
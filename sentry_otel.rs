@@ -1,5 +1,6 @@
 use opentelemetry::trace::TraceContextExt;
 
+// 現在のhubのscopeに書き込むので、並行して動く処理では `Hub::new_from_top` したhubの中で呼ぶ
 pub fn set_otel_sentry_scope() {
     sentry::configure_scope(|scope| {
         let otel_context = opentelemetry::Context::current();
@@ -1,26 +1,117 @@
 use axum::Router;
-use std::net::SocketAddr;
+use std::net::{Ipv6Addr, SocketAddr};
 use tokio::signal;
-use tracing::{debug, info};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tracing::{debug, error, info};
 
 pub async fn run(router: Router, port: Option<u16>) -> anyhow::Result<()> {
-    let addr = SocketAddr::from(([0, 0, 0, 0], port.unwrap_or(8000)));
-    info!("server listening {:?}", addr);
+    Server::new()
+        .serve(
+            SocketAddr::from(([0, 0, 0, 0], port.unwrap_or(8000))),
+            router,
+        )
+        .run()
+        .await
+}
 
-    let listener = tokio::net::TcpListener::bind(addr).await?;
-    axum::serve(listener, router)
-        .with_graceful_shutdown(shutdown_signal())
-        .await?;
+/// 複数のアドレスでrouterを動かす。shutdownは全listenerで共有する
+///
+/// ```ignore
+/// Server::new()
+///     .serve("[::]:8000".parse()?, app)
+///     .serve("127.0.0.1:9000".parse()?, admin)
+///     .run()
+///     .await
+/// ```
+#[derive(Default)]
+pub struct Server {
+    listeners: Vec<(SocketAddr, Router)>,
+}
 
-    info!("server shutdown");
+impl Server {
+    pub fn new() -> Self {
+        Self::default()
+    }
 
-    if let Some(client) = sentry::Hub::current().client() {
-        client.close(Some(std::time::Duration::from_secs(2)));
+    /// `[::]` はIPv4も受け付ける
+    pub fn serve(mut self, addr: SocketAddr, router: Router) -> Self {
+        self.listeners.push((addr, router));
+        self
     }
 
-    opentelemetry::global::shutdown_tracer_provider();
+    pub async fn run(self) -> anyhow::Result<()> {
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+
+        let mut servers = JoinSet::new();
+        for (addr, router) in self.listeners {
+            let listener = bind(addr)?;
+            info!("server listening {:?}", listener.local_addr()?);
+
+            let mut shutdown_rx = shutdown_rx.clone();
+            servers.spawn(async move {
+                axum::serve(
+                    listener,
+                    router.into_make_service_with_connect_info::<SocketAddr>(),
+                )
+                .with_graceful_shutdown(async move {
+                    let _ = shutdown_rx.wait_for(|shutdown| *shutdown).await;
+                })
+                .await
+            });
+        }
+
+        let signal_tx = shutdown_tx.clone();
+        let signal = tokio::spawn(async move {
+            shutdown_signal().await;
+            let _ = signal_tx.send(true);
+        });
+
+        let mut result = Ok(());
+        while let Some(joined) = servers.join_next().await {
+            let served = match joined {
+                Ok(served) => served.map_err(anyhow::Error::from),
+                Err(err) => Err(anyhow::Error::from(err)),
+            };
+            if let Err(err) = served {
+                // 一つが落ちたら他も止める
+                error!("server error: {err:?}");
+                let _ = shutdown_tx.send(true);
+                if result.is_ok() {
+                    result = Err(err);
+                }
+            }
+        }
+        signal.abort();
+
+        info!("server shutdown");
 
-    Ok(())
+        if let Some(client) = sentry::Hub::current().client() {
+            client.close(Some(std::time::Duration::from_secs(2)));
+        }
+
+        opentelemetry::global::shutdown_tracer_provider();
+
+        result
+    }
+}
+
+fn bind(addr: SocketAddr) -> anyhow::Result<tokio::net::TcpListener> {
+    let socket = socket2::Socket::new(
+        socket2::Domain::for_address(addr),
+        socket2::Type::STREAM,
+        Some(socket2::Protocol::TCP),
+    )?;
+    // OSの設定によらず `[::]` ではdual-stackにする
+    if addr.ip() == Ipv6Addr::UNSPECIFIED {
+        socket.set_only_v6(false)?;
+    }
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    Ok(tokio::net::TcpListener::from_std(socket.into())?)
 }
 
 async fn shutdown_signal() {
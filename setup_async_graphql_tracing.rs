@@ -6,9 +6,21 @@ use async_graphql::SchemaBuilder;
 pub fn add_extension<Q, M, S>(
     setup_guard: &super::setup_tracing::SetupGuard,
     schema_builder: SchemaBuilder<Q, M, S>,
+) -> SchemaBuilder<Q, M, S> {
+    add_extension_with(
+        setup_guard,
+        schema_builder,
+        async_graphql_sentry_extension::Sentry::new(),
+    )
+}
+
+pub fn add_extension_with<Q, M, S>(
+    setup_guard: &super::setup_tracing::SetupGuard,
+    schema_builder: SchemaBuilder<Q, M, S>,
+    sentry: async_graphql_sentry_extension::Sentry,
 ) -> SchemaBuilder<Q, M, S> {
     let schema_builder = if setup_guard.sentry_guard.is_some() {
-        schema_builder.extension(async_graphql_sentry_extension::Sentry)
+        schema_builder.extension(sentry)
     } else {
         schema_builder
     };
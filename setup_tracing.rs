@@ -59,6 +59,85 @@ fn get_provider_builder() -> anyhow::Result<Option<opentelemetry_sdk::trace::Bui
     )
 }
 
+type BoxedPropagator = Box<dyn opentelemetry::propagation::TextMapPropagator + Send + Sync>;
+
+// https://opentelemetry.io/docs/specs/otel/configuration/sdk-environment-variables/#general-sdk-configuration
+// b3/b3multiはopentelemetry-zipkin、jaegerはopentelemetry-jaeger-propagator、xrayはopentelemetry-awsを使う
+fn get_propagator() -> (
+    opentelemetry::propagation::TextMapCompositePropagator,
+    Vec<String>,
+) {
+    let names = std::env::var("OTEL_PROPAGATORS").unwrap_or("tracecontext,baggage".to_string());
+    let mut propagators: Vec<BoxedPropagator> = Vec::new();
+    let mut unsupported = Vec::new();
+    for name in names
+        .split(',')
+        .map(|name| name.trim().to_ascii_lowercase())
+    {
+        match name.as_str() {
+            "" | "none" => {}
+            "tracecontext" => propagators.push(Box::new(
+                opentelemetry_sdk::propagation::TraceContextPropagator::new(),
+            )),
+            "baggage" => propagators.push(Box::new(
+                opentelemetry_sdk::propagation::BaggagePropagator::new(),
+            )),
+            "b3" => propagators.push(Box::new(opentelemetry_zipkin::Propagator::with_encoding(
+                opentelemetry_zipkin::B3Encoding::SingleHeader,
+            ))),
+            "b3multi" => {
+                propagators.push(Box::new(opentelemetry_zipkin::Propagator::with_encoding(
+                    opentelemetry_zipkin::B3Encoding::MultipleHeader,
+                )))
+            }
+            "jaeger" => {
+                propagators.push(Box::new(opentelemetry_jaeger_propagator::Propagator::new()))
+            }
+            "xray" => {
+                propagators.push(Box::new(opentelemetry_aws::trace::XrayPropagator::default()))
+            }
+            _ => unsupported.push(name),
+        }
+    }
+    (
+        opentelemetry::propagation::TextMapCompositePropagator::new(propagators),
+        unsupported,
+    )
+}
+
+// spanの中を探さなくてもよいように、request_idをJSONのトップレベルに出す
+#[cfg(not(debug_assertions))]
+struct RequestIdFormat<F>(F);
+
+#[cfg(not(debug_assertions))]
+impl<S, N, F> tracing_subscriber::fmt::FormatEvent<S, N> for RequestIdFormat<F>
+where
+    S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+    N: for<'a> tracing_subscriber::fmt::FormatFields<'a> + 'static,
+    F: tracing_subscriber::fmt::FormatEvent<S, N>,
+{
+    fn format_event(
+        &self,
+        ctx: &tracing_subscriber::fmt::FmtContext<'_, S, N>,
+        mut writer: tracing_subscriber::fmt::format::Writer<'_>,
+        event: &tracing::Event<'_>,
+    ) -> std::fmt::Result {
+        use std::fmt::Write;
+        use tracing_subscriber::fmt::format::Writer;
+
+        let Some(request_id) = super::request_id::RequestId::current() else {
+            return self.0.format_event(ctx, writer, event);
+        };
+        let mut line = String::new();
+        self.0.format_event(ctx, Writer::new(&mut line), event)?;
+        // RequestIdはエスケープの要らない文字だけなのでそのまま埋め込める
+        match line.strip_prefix('{') {
+            Some(rest) => write!(writer, "{{\"request_id\":\"{}\",{}", request_id, rest),
+            None => writer.write_str(&line),
+        }
+    }
+}
+
 pub fn setup() -> anyhow::Result<SetupGuard> {
     let provider = if let Some(provider_builder) = get_provider_builder()? {
         let service_name = if let Ok(service_name) = std::env::var("OTEL_SERVICE_NAME") {
@@ -67,10 +146,6 @@ pub fn setup() -> anyhow::Result<SetupGuard> {
             std::env::var("HOSTNAME").unwrap_or("not-set".to_string())
         };
 
-        opentelemetry::global::set_text_map_propagator(
-            opentelemetry_sdk::propagation::TraceContextPropagator::new(),
-        );
-
         let provider = provider_builder
             .with_config(
                 opentelemetry_sdk::trace::Config::default()
@@ -112,11 +187,18 @@ pub fn setup() -> anyhow::Result<SetupGuard> {
                     .with_level(true)
                     .with_current_span(true)
                     .with_span_list(false)
-                    .flatten_event(true),
+                    .flatten_event(true)
+                    .map_event_format(RequestIdFormat),
             )
             .with(tracing_subscriber::EnvFilter::from_default_env());
 
-        let builder = builder.with(sentry_tracing::layer());
+        let builder = builder.with(sentry_tracing::layer().event_filter(|metadata| {
+            if metadata.target() == super::axum_anyhow_error::LOG_TARGET {
+                sentry_tracing::EventFilter::Ignore
+            } else {
+                sentry_tracing::default_event_filter(metadata)
+            }
+        }));
 
         // TODO: もっときれいにかけないものか
         if let Some(provider) = provider.as_ref() {
@@ -130,6 +212,14 @@ pub fn setup() -> anyhow::Result<SetupGuard> {
             builder.try_init()?;
         }
     }
+    if provider.is_some() {
+        // subscriberを設定した後でないとwarnが出ない
+        let (propagator, unsupported) = get_propagator();
+        for name in unsupported {
+            warn!("unsupported OTEL_PROPAGATORS value: {name}");
+        }
+        opentelemetry::global::set_text_map_propagator(propagator);
+    }
     Ok(SetupGuard {
         sentry_guard: if let Ok(sentry_dsn) = std::env::var("SENTRY_DSN") {
             Some(sentry::init(sentry_dsn))
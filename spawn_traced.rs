@@ -0,0 +1,153 @@
+use opentelemetry::trace::{FutureExt, TraceContextExt};
+use sentry::SentryFutureExt;
+use tokio::task::{AbortHandle, JoinError, JoinHandle, JoinSet};
+use tracing::Instrument;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use super::parent_trace_context::current_context;
+use super::request_id::RequestId;
+
+/// spawnしたタスクのspanと呼び出し元のspanの関係
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SpanRelation {
+    /// 呼び出し元の子にする。レスポンスを返した後も続く処理ではtraceが長くなる
+    #[default]
+    Child,
+    /// 新しいtraceにして呼び出し元へlinkを張る。fire-and-forgetの処理向け
+    Link,
+}
+
+/// 現在のspan、OpenTelemetryのcontext、Sentryのhub、request idを引き継いでspawnする
+pub fn spawn_traced<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    tokio::spawn(traced(tracing::Span::current(), current_context(), future))
+}
+
+/// `name` のspanを作ってspawnする
+pub fn spawn_traced_with<F>(
+    name: &'static str,
+    relation: SpanRelation,
+    future: F,
+) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let span = task_span(name, relation);
+    let otel_cx = span.context();
+    tokio::spawn(traced(span, otel_cx, future))
+}
+
+fn task_span(name: &'static str, relation: SpanRelation) -> tracing::Span {
+    match relation {
+        SpanRelation::Child => {
+            let span = tracing::info_span!("task", otel.name = name);
+            // resolverの中ではOTelのspanがtracingのspanより内側にある
+            span.set_parent(current_context());
+            span
+        }
+        SpanRelation::Link => {
+            let span = tracing::info_span!(parent: None, "task", otel.name = name);
+            let span_context = current_context().span().span_context().clone();
+            if span_context.is_valid() {
+                span.add_link(span_context);
+            }
+            span
+        }
+    }
+}
+
+fn traced<F>(
+    span: tracing::Span,
+    otel_cx: opentelemetry::Context,
+    future: F,
+) -> impl Future<Output = F::Output> + Send + 'static
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    // scopeへの書き込みが呼び出し元に漏れないように分ける
+    let hub = sentry::Hub::new_from_top(sentry::Hub::current());
+    let request_id = RequestId::current();
+    async move {
+        match request_id {
+            Some(request_id) => request_id.scope(future).await,
+            None => future.await,
+        }
+    }
+    .bind_hub(hub)
+    .with_context(otel_cx)
+    .instrument(span)
+}
+
+/// `JoinSet` のspawnを `spawn_traced` にしたもの
+pub struct TracedJoinSet<T> {
+    inner: JoinSet<T>,
+}
+
+impl<T: Send + 'static> TracedJoinSet<T> {
+    pub fn new() -> Self {
+        Self {
+            inner: JoinSet::new(),
+        }
+    }
+
+    pub fn spawn<F>(&mut self, future: F) -> AbortHandle
+    where
+        F: Future<Output = T> + Send + 'static,
+    {
+        self.inner
+            .spawn(traced(tracing::Span::current(), current_context(), future))
+    }
+
+    pub fn spawn_with<F>(
+        &mut self,
+        name: &'static str,
+        relation: SpanRelation,
+        future: F,
+    ) -> AbortHandle
+    where
+        F: Future<Output = T> + Send + 'static,
+    {
+        let span = task_span(name, relation);
+        let otel_cx = span.context();
+        self.inner.spawn(traced(span, otel_cx, future))
+    }
+
+    pub async fn join_next(&mut self) -> Option<Result<T, JoinError>> {
+        self.inner.join_next().await
+    }
+
+    pub async fn join_all(self) -> Vec<T> {
+        self.inner.join_all().await
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    pub fn abort_all(&mut self) {
+        self.inner.abort_all();
+    }
+
+    pub async fn shutdown(&mut self) {
+        self.inner.shutdown().await;
+    }
+
+    pub fn into_inner(self) -> JoinSet<T> {
+        self.inner
+    }
+}
+
+impl<T: Send + 'static> Default for TracedJoinSet<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}